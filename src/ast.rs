@@ -0,0 +1,239 @@
+use crate::{
+    chunk::{OpCode, Value, Variant},
+    objects::FunctionType,
+    token::Token,
+    types::Type,
+};
+
+/// A statement as the parser reads it. The checker and the compiler both
+/// walk the same tree, so it keeps the tokens that diagnostics and runtime
+/// errors point at.
+pub enum Stmt {
+    Expression(Expr),
+    Print(Expr),
+    Var {
+        name: Token,
+        mutable: bool,
+        annotation: Option<TypeExpr>,
+        value: Expr,
+    },
+    Fun(Fun),
+    Class(Class),
+    Return {
+        keyword: Token,
+        value: Option<Expr>,
+    },
+    Break(Token),
+    Continue(Token),
+    If {
+        condition: Expr,
+        then_branch: Vec<Stmt>,
+        else_branch: Option<Vec<Stmt>>,
+    },
+    While {
+        condition: Expr,
+        body: Vec<Stmt>,
+    },
+    For {
+        initializer: Option<Box<Stmt>>,
+        condition: Option<Expr>,
+        increment: Option<Expr>,
+        body: Vec<Stmt>,
+    },
+    ForIn {
+        name: Token,
+        collection: Expr,
+        body: Vec<Stmt>,
+    },
+    /// A `do` block in statement position.
+    Block(Vec<Stmt>),
+    Try {
+        keyword: Token,
+        body: Vec<Stmt>,
+        name: Token,
+        handler: Vec<Stmt>,
+    },
+    Throw {
+        keyword: Token,
+        value: Expr,
+    },
+    Match(Match<Vec<Stmt>>),
+    /// A statement with a syntax error in it. Only the statements nested in
+    /// it are checked, and it is never compiled.
+    Broken(Box<Stmt>),
+    /// Tokens that do not start a statement at all.
+    Error,
+}
+
+pub struct Expr {
+    pub kind: ExprKind,
+    /// The first and last tokens of the expression, which diagnostics
+    /// about the whole expression point at.
+    pub first: Token,
+    pub last: Token,
+}
+
+pub enum ExprKind {
+    Literal(Value),
+    /// A string with embedded expressions, as the parts to join.
+    Interpolation(Vec<Expr>),
+    Variable(Token),
+    SelfKw(Token),
+    Super {
+        keyword: Token,
+        method: Token,
+    },
+    /// An assignment to a variable. `op` is the operation that a compound
+    /// assignment such as `+=` applies, and the value of `x++` and `x--` is
+    /// a literal `1`.
+    Assign {
+        name: Token,
+        operator: Token,
+        op: Option<OpCode>,
+        value: Box<Expr>,
+    },
+    Get {
+        object: Box<Expr>,
+        name: Token,
+    },
+    Set {
+        object: Box<Expr>,
+        name: Token,
+        operator: Token,
+        op: Option<OpCode>,
+        value: Box<Expr>,
+    },
+    Index {
+        collection: Box<Expr>,
+        bracket: Token,
+        index: Box<Expr>,
+    },
+    SetIndex {
+        collection: Box<Expr>,
+        bracket: Token,
+        index: Box<Expr>,
+        operator: Token,
+        op: Option<OpCode>,
+        value: Box<Expr>,
+    },
+    Call {
+        callee: Box<Expr>,
+        paren: Token,
+        args: Vec<Expr>,
+        /// The names of the trailing arguments that were passed by name.
+        names: Vec<String>,
+    },
+    Unary {
+        operator: Token,
+        op: OpCode,
+        operand: Box<Expr>,
+    },
+    /// `!=`, `<=` and `>=` apply `op` and then negate the result.
+    Binary {
+        operator: Token,
+        op: OpCode,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    /// `and` or `or`, which only evaluate their right operand when needed.
+    Logical {
+        operator: Token,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    Contains {
+        operator: Token,
+        item: Box<Expr>,
+        collection: Box<Expr>,
+    },
+    List(Vec<Expr>),
+    Map(Vec<(Expr, Expr)>),
+    If {
+        condition: Box<Expr>,
+        then_branch: Block,
+        else_branch: Option<Block>,
+    },
+    Do(Block),
+    Match(Box<Match<Block>>),
+    Wrap {
+        variant: Variant,
+        value: Box<Expr>,
+    },
+    None,
+    /// The postfix `?`.
+    Propagate {
+        operator: Token,
+        value: Box<Expr>,
+    },
+    Lambda(Box<Fun>),
+    /// An expression that could not be parsed.
+    Error,
+}
+
+/// The statements of a block whose value is that of its last statement,
+/// when that is an expression.
+pub struct Block {
+    pub stmts: Vec<Stmt>,
+    pub value: Option<Box<Expr>>,
+}
+
+pub struct Match<B> {
+    pub keyword: Token,
+    pub subject: Expr,
+    pub cases: Vec<Case<B>>,
+}
+
+pub struct Case<B> {
+    pub keyword: Token,
+    /// Missing when the pattern could not be parsed.
+    pub pattern: Option<Pattern>,
+    pub body: B,
+}
+
+pub struct Pattern {
+    pub variant: Variant,
+    pub token: Token,
+    /// The variable the value inside the variant is bound to.
+    pub binding: Option<Token>,
+}
+
+pub struct Fun {
+    pub kind: FunctionType,
+    pub name: Token,
+    pub params: Vec<Param>,
+    pub rest: Option<Param>,
+    pub ret: Option<TypeExpr>,
+    /// Only anonymous functions give their body a value.
+    pub body: Block,
+    /// The token that ends the function, where the body's value is returned.
+    pub end: Token,
+}
+
+pub struct Param {
+    pub name: Token,
+    pub annotation: Option<TypeExpr>,
+    pub default: Option<Expr>,
+}
+
+pub struct Class {
+    pub name: Token,
+    pub superclass: Option<Token>,
+    pub methods: Vec<Fun>,
+}
+
+/// A type annotation. Class names are only resolved by the checker, so
+/// that a class can be used in annotations before it is declared.
+pub enum TypeExpr {
+    Builtin(Type),
+    Class(Token),
+    Fun(Vec<TypeExpr>, Box<TypeExpr>),
+    List(Box<TypeExpr>),
+    Map {
+        key: Box<TypeExpr>,
+        /// The last token of the key type, where an invalid key is reported.
+        key_end: Token,
+        value: Box<TypeExpr>,
+    },
+    Option(Box<TypeExpr>),
+    Result(Box<TypeExpr>, Box<TypeExpr>),
+}
@@ -0,0 +1,350 @@
+use std::{
+    cmp::Ordering,
+    fmt::{self, Display},
+    ops::{Add, Mul, Neg, Sub},
+};
+
+/// An integer of any size, stored as a sign and the base 2^32 digits of its
+/// magnitude, least significant first. Zero has no digits and is never
+/// negative, so that equal numbers have equal representations.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct BigInt {
+    negative: bool,
+    digits: Vec<u32>,
+}
+
+impl BigInt {
+    fn from_parts(negative: bool, mut digits: Vec<u32>) -> Self {
+        while digits.last() == Some(&0) {
+            digits.pop();
+        }
+        BigInt {
+            negative: negative && !digits.is_empty(),
+            digits,
+        }
+    }
+
+    /// Parses a decimal integer with an optional leading `-`.
+    pub fn parse(text: &str) -> Option<Self> {
+        let (negative, text) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text),
+        };
+        if text.is_empty() {
+            return None;
+        }
+
+        let mut digits = Vec::new();
+        for c in text.chars() {
+            mul_small_add(&mut digits, 10, c.to_digit(10)?);
+        }
+        Some(BigInt::from_parts(negative, digits))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.digits.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    pub fn to_isize(&self) -> Option<isize> {
+        if self.digits.len() > 2 {
+            return None;
+        }
+        let magnitude = self
+            .digits
+            .iter()
+            .rev()
+            .fold(0i128, |acc, &digit| (acc << 32) | digit as i128);
+        let value = if self.negative { -magnitude } else { magnitude };
+        isize::try_from(value).ok()
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        let magnitude = self
+            .digits
+            .iter()
+            .rev()
+            .fold(0.0, |acc, &digit| acc * 4294967296.0 + digit as f64);
+        if self.negative {
+            -magnitude
+        } else {
+            magnitude
+        }
+    }
+
+    /// Divides towards zero, returning the quotient and the remainder. The
+    /// remainder has the same sign as `self`.
+    pub fn div_rem(&self, divisor: &BigInt) -> (BigInt, BigInt) {
+        assert!(!divisor.is_zero(), "BigInt division by zero");
+        let (quotient, remainder) = div_rem_magnitude(&self.digits, &divisor.digits);
+        (
+            BigInt::from_parts(self.negative != divisor.negative, quotient),
+            BigInt::from_parts(self.negative, remainder),
+        )
+    }
+
+    pub fn pow(&self, mut exponent: u32) -> BigInt {
+        let mut result = BigInt::from(1);
+        let mut base = self.clone();
+        while exponent > 0 {
+            if exponent & 1 == 1 {
+                result = &result * &base;
+            }
+            exponent >>= 1;
+            if exponent > 0 {
+                base = &base * &base;
+            }
+        }
+        result
+    }
+}
+
+impl From<isize> for BigInt {
+    fn from(value: isize) -> Self {
+        let magnitude = (value as i128).unsigned_abs();
+        let digits = vec![magnitude as u32, (magnitude >> 32) as u32];
+        BigInt::from_parts(value < 0, digits)
+    }
+}
+
+impl Neg for &BigInt {
+    type Output = BigInt;
+
+    fn neg(self) -> BigInt {
+        BigInt::from_parts(!self.negative, self.digits.clone())
+    }
+}
+
+impl Add for &BigInt {
+    type Output = BigInt;
+
+    fn add(self, other: &BigInt) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt::from_parts(self.negative, add_magnitude(&self.digits, &other.digits));
+        }
+        match compare_magnitude(&self.digits, &other.digits) {
+            Ordering::Less => {
+                BigInt::from_parts(other.negative, sub_magnitude(&other.digits, &self.digits))
+            }
+            _ => BigInt::from_parts(self.negative, sub_magnitude(&self.digits, &other.digits)),
+        }
+    }
+}
+
+impl Sub for &BigInt {
+    type Output = BigInt;
+
+    fn sub(self, other: &BigInt) -> BigInt {
+        self + &-other
+    }
+}
+
+impl Mul for &BigInt {
+    type Output = BigInt;
+
+    fn mul(self, other: &BigInt) -> BigInt {
+        let mut digits = vec![0u32; self.digits.len() + other.digits.len()];
+        for (i, &a) in self.digits.iter().enumerate() {
+            let mut carry = 0u64;
+            for (j, &b) in other.digits.iter().enumerate() {
+                let product = a as u64 * b as u64 + digits[i + j] as u64 + carry;
+                digits[i + j] = product as u32;
+                carry = product >> 32;
+            }
+            digits[i + other.digits.len()] = carry as u32;
+        }
+        BigInt::from_parts(self.negative != other.negative, digits)
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => compare_magnitude(&self.digits, &other.digits),
+            (true, true) => compare_magnitude(&other.digits, &self.digits),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Display for BigInt {
+    fn fmt(&self, format: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_zero() {
+            return write!(format, "0");
+        }
+
+        // Peel off nine decimal digits at a time, least significant first
+        let mut chunks = Vec::new();
+        let mut magnitude = self.digits.clone();
+        while !magnitude.is_empty() {
+            chunks.push(div_small(&mut magnitude, 1_000_000_000));
+        }
+
+        if self.negative {
+            write!(format, "-")?;
+        }
+        write!(format, "{}", chunks.last().unwrap())?;
+        for chunk in chunks.iter().rev().skip(1) {
+            write!(format, "{:09}", chunk)?;
+        }
+        Ok(())
+    }
+}
+
+fn compare_magnitude(a: &[u32], b: &[u32]) -> Ordering {
+    a.len()
+        .cmp(&b.len())
+        .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_magnitude(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut digits = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = 0u64;
+    for i in 0..a.len().max(b.len()) {
+        let sum = *a.get(i).unwrap_or(&0) as u64 + *b.get(i).unwrap_or(&0) as u64 + carry;
+        digits.push(sum as u32);
+        carry = sum >> 32;
+    }
+    digits.push(carry as u32);
+    digits
+}
+
+/// Subtracts `b` from `a`, which must be at least as large as `b`.
+fn sub_magnitude(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut digits = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+    for (i, &digit) in a.iter().enumerate() {
+        let mut difference = digit as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+        borrow = 0;
+        if difference < 0 {
+            difference += 1 << 32;
+            borrow = 1;
+        }
+        digits.push(difference as u32);
+    }
+    digits
+}
+
+fn mul_small_add(digits: &mut Vec<u32>, factor: u32, addend: u32) {
+    let mut carry = addend as u64;
+    for digit in digits.iter_mut() {
+        let product = *digit as u64 * factor as u64 + carry;
+        *digit = product as u32;
+        carry = product >> 32;
+    }
+    if carry > 0 {
+        digits.push(carry as u32);
+    }
+}
+
+/// Divides `digits` in place and returns the remainder.
+fn div_small(digits: &mut Vec<u32>, divisor: u32) -> u32 {
+    let mut remainder = 0u64;
+    for digit in digits.iter_mut().rev() {
+        let current = (remainder << 32) | *digit as u64;
+        *digit = (current / divisor as u64) as u32;
+        remainder = current % divisor as u64;
+    }
+    while digits.last() == Some(&0) {
+        digits.pop();
+    }
+    remainder as u32
+}
+
+/// Long division, one bit at a time.
+fn div_rem_magnitude(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if b.len() == 1 {
+        let mut quotient = a.to_vec();
+        let remainder = div_small(&mut quotient, b[0]);
+        return (quotient, vec![remainder]);
+    }
+
+    let mut quotient = vec![0u32; a.len()];
+    let mut remainder: Vec<u32> = Vec::new();
+    for bit in (0..a.len() * 32).rev() {
+        // remainder = remainder * 2 + the next bit of `a`
+        mul_small_add(&mut remainder, 2, (a[bit / 32] >> (bit % 32)) & 1);
+        if compare_magnitude(&remainder, b) != Ordering::Less {
+            remainder = sub_magnitude(&remainder, b);
+            while remainder.last() == Some(&0) {
+                remainder.pop();
+            }
+            quotient[bit / 32] |= 1 << (bit % 32);
+        }
+    }
+    (quotient, remainder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BigInt;
+
+    fn big(text: &str) -> BigInt {
+        BigInt::parse(text).unwrap()
+    }
+
+    #[test]
+    fn parse_and_display() {
+        for text in [
+            "0",
+            "7",
+            "-42",
+            "18446744073709551616",
+            "-123456789012345678901234567890",
+        ] {
+            assert_eq!(text, big(text).to_string());
+        }
+        assert_eq!("0", big("-0").to_string());
+        assert_eq!(None, BigInt::parse("12a"));
+    }
+
+    #[test]
+    fn arithmetic() {
+        let a = big("99999999999999999999");
+        let b = big("-12345678901234567890");
+        assert_eq!("87654321098765432109", (&a + &b).to_string());
+        assert_eq!("112345678901234567889", (&a - &b).to_string());
+        assert_eq!(
+            "-1234567890123456788987654321098765432110",
+            (&a * &b).to_string()
+        );
+        assert_eq!(
+            "1267650600228229401496703205376",
+            big("2").pow(100).to_string()
+        );
+    }
+
+    #[test]
+    fn division() {
+        let (quotient, remainder) = big("-100000000000000000000").div_rem(&big("30000000000"));
+        assert_eq!("-3333333333", quotient.to_string());
+        assert_eq!("-10000000000", remainder.to_string());
+
+        let (quotient, remainder) = big("100000000000000000007").div_rem(&big("10"));
+        assert_eq!("10000000000000000000", quotient.to_string());
+        assert_eq!("7", remainder.to_string());
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(Some(isize::MIN), BigInt::from(isize::MIN).to_isize());
+        assert_eq!(Some(isize::MAX), BigInt::from(isize::MAX).to_isize());
+        assert_eq!(
+            None,
+            (&BigInt::from(isize::MAX) + &BigInt::from(1)).to_isize()
+        );
+        assert!(big("-5") < big("3"));
+        assert!(big("-50000000000000000000") < big("-5"));
+        assert_eq!(1e20, big("100000000000000000000").to_f64());
+    }
+}
@@ -0,0 +1,1828 @@
+use crate::{
+    ast::{Block, Class, Expr, ExprKind, Fun, Match, Param, Pattern, Stmt, TypeExpr},
+    chunk::{OpCode, Variant},
+    objects::FunctionType,
+    result::{Diagnostic, Label, Severity},
+    token::{Token, TokenType},
+    types::{self, Signature, Type},
+};
+use std::{collections::HashMap, mem, rc::Rc};
+
+/// How many times the program is checked at most. Each pass knows the types
+/// of the globals that the pass before it found, so a function can call
+/// another that is declared after it, which in turn can call a third.
+const MAX_PASSES: usize = 4;
+
+#[derive(Clone)]
+struct Local {
+    name: Token,
+    depth: Option<usize>,
+    mutable: bool,
+    ty: Type,
+    annotated: bool,
+    /// Where the type of an unannotated local was inferred, for diagnostics:
+    /// its declaration, or for a parameter the use that last narrowed it.
+    inferred_at: Option<Token>,
+    /// Whether this is an unannotated parameter, whose type is inferred from
+    /// how the function uses it.
+    inferred: bool,
+    /// The type that every use of an inferred parameter so far allows, or
+    /// `None` once two of them conflict.
+    required: Option<Type>,
+}
+
+impl Local {
+    fn new(name: Token, mutable: bool) -> Self {
+        Local {
+            name,
+            depth: None,
+            mutable,
+            ty: Type::Any,
+            annotated: false,
+            inferred_at: None,
+            inferred: false,
+            required: Some(Type::Any),
+        }
+    }
+}
+
+struct Level {
+    function_type: FunctionType,
+    locals: Vec<Local>,
+    scope_depth: usize,
+    /// The names of the positional parameters.
+    params: Vec<String>,
+    defaults: usize,
+    variadic: bool,
+    /// The annotated return type, or the type inferred from the first
+    /// `return` when there is no annotation.
+    return_type: Option<Type>,
+    return_annotated: bool,
+    return_inferred_at: Option<Token>,
+    has_return: bool,
+    /// Whether every path through the statement checked last ends in a
+    /// `return` or a `throw`.
+    ends_with_return: bool,
+    /// How many loops the code being checked is inside of.
+    loops: usize,
+}
+
+impl Level {
+    fn new(function_type: FunctionType) -> Self {
+        let mut receiver = Local::new(
+            Token {
+                id: TokenType::Error,
+                lexeme: String::new(),
+                line: 0,
+                column: 0,
+                span: Default::default(),
+            },
+            false,
+        );
+        receiver.depth = Some(0);
+        if function_type == FunctionType::Method || function_type == FunctionType::Initializer {
+            receiver.name.lexeme = String::from("self");
+        }
+
+        Level {
+            function_type,
+            locals: vec![receiver],
+            scope_depth: 0,
+            params: Vec::new(),
+            defaults: 0,
+            variadic: false,
+            return_type: None,
+            return_annotated: false,
+            return_inferred_at: None,
+            has_return: false,
+            ends_with_return: false,
+            loops: 0,
+        }
+    }
+}
+
+struct ClassLevel {
+    name: String,
+    has_superclass: bool,
+}
+
+/// The type of a value, along with the variable it was read from so that
+/// the variable's type can be refined or reported.
+struct StackType {
+    ty: Type,
+    variable: Option<Token>,
+}
+
+impl From<Type> for StackType {
+    fn from(ty: Type) -> Self {
+        StackType { ty, variable: None }
+    }
+}
+
+/// What the type checker knows about a class declared in this program.
+#[derive(Clone, PartialEq)]
+struct ClassType {
+    superclass: Option<String>,
+    /// Set when the superclass is only known at runtime, so the inherited
+    /// initializer cannot be checked.
+    dynamic_superclass: bool,
+    init: Option<Rc<Signature>>,
+}
+
+/// What a pass over the program found out about its globals and classes,
+/// for the next pass to use before their declarations.
+#[derive(Default)]
+struct Hints {
+    /// The types of the globals declared once at the top level.
+    globals: HashMap<String, Type>,
+    /// The declarations of the unannotated globals declared once.
+    inferred: HashMap<String, Token>,
+    /// The types of the values assigned to unannotated variables, by the
+    /// position of their declaration. Joined with the type of the initial
+    /// value, they make up the type of the variable.
+    assigned: HashMap<(usize, usize), Type>,
+    /// The globals whose last declaration is a `let`.
+    immutable: HashMap<String, Token>,
+    classes: HashMap<String, ClassType>,
+}
+
+impl Hints {
+    fn same_types(&self, other: &Hints) -> bool {
+        self.globals == other.globals
+            && self.assigned == other.assigned
+            && self.classes == other.classes
+    }
+}
+
+struct Checker {
+    levels: Vec<Level>,
+    classes: Vec<ClassLevel>,
+    immutable_globals: HashMap<String, Token>,
+    global_types: HashMap<String, Type>,
+    global_inferred: HashMap<String, Token>,
+    /// The types of the values assigned to unannotated variables so far, by
+    /// the position of their declaration.
+    assigned: HashMap<(usize, usize), Type>,
+    /// How many times each global is declared at the top level.
+    global_declarations: HashMap<String, usize>,
+    class_types: HashMap<String, ClassType>,
+    hints: Hints,
+    diagnostics: Vec<Diagnostic>,
+    /// Set once a statement has an error, so that its other errors, which
+    /// are most likely caused by the first, are not reported.
+    panic_mode: bool,
+}
+
+impl Checker {
+    fn new(hints: Hints) -> Self {
+        Checker {
+            levels: vec![Level::new(FunctionType::Script)],
+            classes: Vec::new(),
+            immutable_globals: HashMap::new(),
+            global_types: HashMap::new(),
+            global_inferred: HashMap::new(),
+            assigned: HashMap::new(),
+            global_declarations: HashMap::new(),
+            class_types: hints.classes.clone(),
+            hints,
+            diagnostics: Vec::new(),
+            panic_mode: false,
+        }
+    }
+
+    fn check(&mut self, program: &[Stmt]) {
+        for stmt in program {
+            self.declaration(stmt);
+        }
+    }
+
+    /// The hints for the next pass.
+    fn hints(&self) -> Hints {
+        let declared_once = |name: &String| self.global_declarations.get(name) == Some(&1);
+        let globals = self
+            .global_types
+            .iter()
+            .filter(|(name, _)| declared_once(name))
+            .map(|(name, ty)| (name.clone(), ty.clone()))
+            .collect();
+        let inferred = self
+            .global_inferred
+            .iter()
+            .filter(|(name, _)| declared_once(name))
+            .map(|(name, at)| (name.clone(), at.clone()))
+            .collect();
+        Hints {
+            globals,
+            inferred,
+            assigned: self.assigned.clone(),
+            immutable: self.immutable_globals.clone(),
+            classes: self.class_types.clone(),
+        }
+    }
+
+    fn level(&self) -> &Level {
+        self.levels.last().unwrap()
+    }
+
+    fn level_mut(&mut self) -> &mut Level {
+        self.levels.last_mut().unwrap()
+    }
+
+    fn declaration(&mut self, stmt: &Stmt) {
+        // Only the syntax error of a broken statement is reported
+        let stmt = match stmt {
+            Stmt::Broken(stmt) => {
+                self.panic_mode = true;
+                stmt.as_ref()
+            }
+            stmt => stmt,
+        };
+        // These statements set `ends_with_return` themselves
+        let can_return = matches!(
+            stmt,
+            Stmt::Return { .. }
+                | Stmt::Throw { .. }
+                | Stmt::If { .. }
+                | Stmt::Block(_)
+                | Stmt::Try { .. }
+                | Stmt::Match(_)
+        );
+        self.level_mut().ends_with_return = false;
+
+        match stmt {
+            Stmt::Expression(expr) | Stmt::Print(expr) => {
+                self.expression(expr);
+            }
+            Stmt::Var {
+                name,
+                mutable,
+                annotation,
+                value,
+            } => self.var_declaration(name, *mutable, annotation.as_ref(), value),
+            Stmt::Fun(fun) => self.fun_declaration(fun),
+            Stmt::Class(class) => self.class_declaration(class),
+            Stmt::Return { keyword, value } => self.return_statement(keyword, value.as_ref()),
+            Stmt::Break(keyword) => self.loop_jump(keyword, "Cannot use 'break' outside of a loop"),
+            Stmt::Continue(keyword) => {
+                self.loop_jump(keyword, "Cannot use 'continue' outside of a loop")
+            }
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => self.if_statement(condition, then_branch, else_branch.as_deref()),
+            Stmt::While {
+                condition, body, ..
+            } => {
+                self.expression(condition);
+                self.level_mut().loops += 1;
+                self.scope_block(body);
+                self.level_mut().loops -= 1;
+            }
+            Stmt::For {
+                initializer,
+                condition,
+                increment,
+                body,
+                ..
+            } => self.for_statement(initializer.as_deref(), condition, increment, body),
+            Stmt::ForIn {
+                name,
+                collection,
+                body,
+            } => self.for_in_statement(name, collection, body),
+            Stmt::Block(stmts) => self.scope_block(stmts),
+            Stmt::Try {
+                body,
+                name,
+                handler,
+                ..
+            } => self.try_statement(body, name, handler),
+            Stmt::Throw { value, .. } => {
+                let thrown = self.expression(value).ty;
+                if let Err(message) = types::throw(&thrown) {
+                    self.error_at(value.last.clone(), &message);
+                }
+                self.level_mut().ends_with_return = true;
+            }
+            Stmt::Match(statement) => self.match_statement(statement),
+            Stmt::Broken(_) | Stmt::Error => (),
+        }
+
+        if !can_return {
+            self.level_mut().ends_with_return = false;
+        }
+        self.panic_mode = false;
+    }
+
+    fn var_declaration(
+        &mut self,
+        name: &Token,
+        mutable: bool,
+        annotation: Option<&TypeExpr>,
+        value: &Expr,
+    ) {
+        self.declare_variable(name, mutable);
+
+        if self.level().scope_depth == 0 {
+            self.global_inferred.remove(&name.lexeme);
+            if mutable {
+                self.immutable_globals.remove(&name.lexeme);
+            } else {
+                self.immutable_globals
+                    .insert(name.lexeme.clone(), name.clone());
+            }
+        }
+
+        let annotation = annotation.map(|annotation| self.resolve_type(annotation));
+        let actual = self.expression(value);
+        let annotated = annotation.is_some();
+        let ty = match annotation {
+            Some(expected) => {
+                self.check_assignment(name, &value.last, &expected, &actual);
+                expected
+            }
+            None => actual.ty,
+        };
+
+        self.set_variable_type(name, ty, annotated);
+        self.mark_initialized();
+    }
+
+    fn class_declaration(&mut self, class: &Class) {
+        let class_name = &class.name;
+        self.declare_variable(class_name, true);
+        if self.level().scope_depth == 0 {
+            self.global_inferred.remove(&class_name.lexeme);
+            self.immutable_globals.remove(&class_name.lexeme);
+        }
+
+        let name = class_name.lexeme.clone();
+        self.class_types.insert(
+            name.clone(),
+            ClassType {
+                superclass: None,
+                dynamic_superclass: false,
+                init: None,
+            },
+        );
+
+        self.set_variable_type(class_name, Type::Class(name.clone()), true);
+        self.mark_initialized();
+
+        self.classes.push(ClassLevel {
+            name: name.clone(),
+            has_superclass: false,
+        });
+
+        if let Some(superclass) = &class.superclass {
+            let ty = self.named_variable(superclass).ty;
+
+            if class_name.lexeme == superclass.lexeme {
+                self.error_at(superclass.clone(), "A class cannot inherit from itself");
+            } else {
+                let class_type = self.class_types.get_mut(&name).unwrap();
+                match ty {
+                    Type::Class(superclass) => class_type.superclass = Some(superclass),
+                    Type::Any => class_type.dynamic_superclass = true,
+                    other => self.error_at(
+                        superclass.clone(),
+                        &format!("Superclass must be a class, found `{}`", other),
+                    ),
+                }
+            }
+
+            self.begin_scope();
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        for method in &class.methods {
+            self.function(method);
+            self.panic_mode = false;
+        }
+
+        if self.classes.pop().unwrap().has_superclass {
+            self.end_scope();
+        }
+    }
+
+    fn fun_declaration(&mut self, fun: &Fun) {
+        self.declare_variable(&fun.name, true);
+        if self.level().scope_depth == 0 {
+            self.global_inferred.remove(&fun.name.lexeme);
+            self.immutable_globals.remove(&fun.name.lexeme);
+        }
+        self.mark_initialized();
+        self.function(fun);
+    }
+
+    fn function(&mut self, fun: &Fun) -> Type {
+        let kind = fun.kind;
+        let class_name = self.classes.last().map(|class| class.name.clone());
+        let mut level = Level::new(kind);
+        if kind == FunctionType::Method || kind == FunctionType::Initializer {
+            level.locals[0].ty = Type::Instance(class_name.clone().unwrap());
+        }
+
+        self.levels.push(level);
+        self.begin_scope();
+
+        let mut params = Vec::new();
+        for param in &fun.params {
+            self.declare_variable(&param.name, true);
+
+            let annotated = param.annotation.is_some();
+            let ty = match &param.annotation {
+                Some(annotation) => self.resolve_type(annotation),
+                None => Type::Any,
+            };
+            if let Some(default) = &param.default {
+                self.default_value(&param.name, &ty, default);
+            }
+
+            let level = self.level_mut();
+            level.params.push(param.name.lexeme.clone());
+            let local = level.locals.last_mut().unwrap();
+            local.ty = ty.clone();
+            local.annotated = annotated;
+            local.inferred = !annotated;
+            params.push(ty);
+
+            self.mark_initialized();
+        }
+        let rest = fun.rest.as_ref().map(|rest| self.rest_parameter(rest));
+
+        let ret = match &fun.ret {
+            Some(ret) => {
+                let ret = self.resolve_type(ret);
+                self.level_mut().return_type = Some(ret.clone());
+                self.level_mut().return_annotated = true;
+                ret
+            }
+            None => Type::Any,
+        };
+
+        // Declare a provisional signature before checking the body so that
+        // recursive calls are checked too.
+        let level = self.level();
+        let signature = Rc::new(Signature {
+            required: level.params.len() - level.defaults,
+            names: level.params.clone(),
+            params,
+            rest,
+            ret,
+            inferred_at: Vec::new(),
+        });
+        self.declare_signature(kind, &fun.name, class_name.as_deref(), signature);
+
+        if kind == FunctionType::Lambda {
+            let value = self.value_block(&fun.body);
+            self.check_return(&fun.name, &fun.end, StackType::from(value));
+            let level = self.level_mut();
+            level.has_return = true;
+            level.ends_with_return = true;
+        } else {
+            self.block(&fun.body.stmts);
+        }
+
+        let signature = Rc::new(self.infer_signature());
+        self.declare_signature(kind, &fun.name, class_name.as_deref(), signature.clone());
+
+        let missing_return = match &signature.ret {
+            Type::Any | Type::Void => None,
+            _ if !self.level().has_return => Some("has no return statement"),
+            _ if !self.level().ends_with_return => Some("can reach its end without returning"),
+            _ => None,
+        };
+        if let Some(missing) = missing_return {
+            self.error_at(
+                fun.name.clone(),
+                &format!(
+                    "Function is declared to return `{}` but {}",
+                    signature.ret, missing
+                ),
+            );
+        }
+
+        self.levels.pop();
+        Type::Fun(Some(signature))
+    }
+
+    /// Checks the default value of the parameter `name`, which can use the
+    /// parameters before it.
+    fn default_value(&mut self, name: &Token, ty: &Type, default: &Expr) {
+        let level = self.levels.pop().unwrap();
+        let mut thunk = Level::new(FunctionType::Lambda);
+        thunk.scope_depth = 1;
+        // The parameter itself is still being declared, so that using it
+        // in its own default is an error
+        thunk.locals.extend(level.locals.iter().skip(1).cloned());
+        self.levels.push(thunk);
+
+        let value = self.expression(default);
+        self.constrain(&value, ty);
+        let actual = value.ty.clone();
+        if !self.is_assignable(ty, &actual) && !self.panic_mode {
+            self.error_at(
+                default.last.clone(),
+                &format!(
+                    "The default value of `{}` must be of type `{}`, but found `{}`",
+                    name.lexeme, ty, actual
+                ),
+            );
+            self.note_inferred(&value);
+        }
+
+        self.levels.pop();
+        self.levels.push(level);
+        self.level_mut().defaults += 1;
+    }
+
+    /// Declares a `...name` parameter, which collects the extra arguments of
+    /// a call into a list, and returns the type of its elements.
+    fn rest_parameter(&mut self, rest: &Param) -> Type {
+        self.declare_variable(&rest.name, true);
+        let ty = match &rest.annotation {
+            Some(annotation) => self.resolve_type(annotation),
+            None => Type::Any,
+        };
+
+        let level = self.level_mut();
+        level.variadic = true;
+        let param = level.locals.last_mut().unwrap();
+        param.ty = Type::List(Box::new(ty.clone()));
+        param.annotated = true;
+        self.mark_initialized();
+        ty
+    }
+
+    fn declare_signature(
+        &mut self,
+        kind: FunctionType,
+        name: &Token,
+        class_name: Option<&str>,
+        signature: Rc<Signature>,
+    ) {
+        match kind {
+            FunctionType::Function => {
+                let enclosing = self.levels.len() - 2;
+                let ty = Type::Fun(Some(signature));
+                if self.levels[enclosing].scope_depth > 0 {
+                    self.levels[enclosing].locals.last_mut().unwrap().ty = ty;
+                } else {
+                    self.global_types.insert(name.lexeme.clone(), ty);
+                }
+            }
+            FunctionType::Initializer => {
+                let class = self.class_types.get_mut(class_name.unwrap()).unwrap();
+                class.init = Some(signature);
+            }
+            _ => (),
+        }
+    }
+
+    /// Builds the signature of the function being checked once its body has
+    /// been checked, using the parameter types inferred from the uses in the
+    /// body and the return type inferred from its `return` statements.
+    fn infer_signature(&self) -> Signature {
+        let level = self.level();
+        let arity = level.params.len();
+        let (params, inferred_at) = level
+            .locals
+            .iter()
+            .skip(1)
+            .take(arity)
+            .map(|param| match &param.required {
+                Some(ty) if param.inferred && *ty != Type::Any => {
+                    (ty.clone(), param.inferred_at.clone())
+                }
+                _ if param.inferred => (Type::Any, None),
+                _ => (param.ty.clone(), None),
+            })
+            .unzip();
+        let rest = match level.locals.get(arity + 1) {
+            Some(Local {
+                ty: Type::List(element),
+                ..
+            }) if level.variadic => Some(element.as_ref().clone()),
+            _ => None,
+        };
+
+        let ret = match &level.return_type {
+            Some(ret) if level.return_annotated => ret.clone(),
+            None => Type::Void,
+            // The body can reach its end and return void instead
+            Some(ret) if *ret != Type::Void && !level.ends_with_return => Type::Any,
+            Some(ret) => ret.clone(),
+        };
+
+        Signature {
+            params,
+            names: level.params.clone(),
+            required: arity - level.defaults,
+            rest,
+            ret,
+            inferred_at,
+        }
+    }
+
+    fn return_statement(&mut self, keyword: &Token, value: Option<&Expr>) {
+        if self.level().function_type == FunctionType::Script {
+            self.error_at(keyword.clone(), "Cannot return from top-level code");
+        }
+        self.level_mut().has_return = true;
+
+        match value {
+            None => self.check_return(keyword, keyword, StackType::from(Type::Void)),
+            Some(value) => {
+                if self.level().function_type == FunctionType::Initializer {
+                    self.error_at(keyword.clone(), "Cannot return a value from an initializer");
+                }
+                let actual = self.expression(value);
+                self.check_return(keyword, &value.last, actual);
+            }
+        }
+        self.level_mut().ends_with_return = true;
+    }
+
+    /// Checks a value the function returns, reporting a mismatch at `at`.
+    /// Without an annotation, the first value returned decides the return
+    /// type, as inferred at `keyword`.
+    fn check_return(&mut self, keyword: &Token, at: &Token, actual: StackType) {
+        let expected = match &self.level().return_type {
+            Some(expected) => expected.clone(),
+            None => {
+                let level = self.level_mut();
+                level.return_type = Some(actual.ty);
+                level.return_inferred_at = Some(keyword.clone());
+                return;
+            }
+        };
+
+        self.constrain(&actual, &expected);
+        if self.is_assignable(&expected, &actual.ty) || self.panic_mode {
+            return;
+        }
+
+        if actual.ty == Type::Void {
+            self.error_at(
+                at.clone(),
+                &format!("Expected a return value of type `{}`", expected),
+            );
+        } else {
+            self.error_at(
+                at.clone(),
+                &format!(
+                    "Expected a return value of type `{}`, but found `{}`",
+                    expected, actual.ty
+                ),
+            );
+        }
+        if let Some(at) = self.level().return_inferred_at.clone() {
+            self.note_at(
+                at,
+                &format!("The return type was inferred to be `{}` here", expected),
+            );
+        }
+        self.note_inferred(&actual);
+    }
+
+    fn loop_jump(&mut self, keyword: &Token, message: &str) {
+        if self.level().loops == 0 {
+            self.error_at(keyword.clone(), message);
+        }
+    }
+
+    fn if_statement(
+        &mut self,
+        condition: &Expr,
+        then_branch: &[Stmt],
+        else_branch: Option<&[Stmt]>,
+    ) {
+        self.expression(condition);
+
+        self.level_mut().ends_with_return = false;
+        self.scope_block(then_branch);
+        let then_returns = self.level().ends_with_return;
+
+        self.level_mut().ends_with_return = false;
+        if let Some(else_branch) = else_branch {
+            self.scope_block(else_branch);
+        }
+        self.level_mut().ends_with_return &= then_returns;
+    }
+
+    fn for_statement(
+        &mut self,
+        initializer: Option<&Stmt>,
+        condition: &Option<Expr>,
+        increment: &Option<Expr>,
+        body: &[Stmt],
+    ) {
+        self.begin_scope();
+
+        match initializer {
+            Some(Stmt::Var {
+                name,
+                mutable,
+                annotation,
+                value,
+            }) => self.var_declaration(name, *mutable, annotation.as_ref(), value),
+            Some(Stmt::Expression(expr)) => {
+                self.expression(expr);
+            }
+            _ => (),
+        }
+        if let Some(condition) = condition {
+            self.expression(condition);
+        }
+        if let Some(increment) = increment {
+            self.expression(increment);
+        }
+
+        self.level_mut().loops += 1;
+        self.scope_block(body);
+        self.level_mut().loops -= 1;
+
+        self.end_scope();
+    }
+
+    fn for_in_statement(&mut self, name: &Token, collection: &Expr, body: &[Stmt]) {
+        self.begin_scope();
+
+        let ty = self.expression(collection).ty;
+        let element = match types::iterate(&ty) {
+            Ok(element) => element,
+            Err(message) => {
+                self.error_at(collection.first.clone(), &message);
+                Type::Any
+            }
+        };
+
+        self.level_mut().loops += 1;
+        self.begin_scope();
+        self.add_local(name, true);
+        self.mark_initialized();
+        self.set_variable_type(name, element, false);
+        self.block(body);
+        self.end_scope();
+        self.level_mut().loops -= 1;
+
+        self.end_scope();
+    }
+
+    fn try_statement(&mut self, body: &[Stmt], name: &Token, handler: &[Stmt]) {
+        self.scope_block(body);
+        let try_returns = self.level().ends_with_return;
+
+        self.begin_scope();
+        self.add_local(name, true);
+        self.mark_initialized();
+        self.set_variable_type(name, Type::Error, false);
+        self.level_mut().ends_with_return = false;
+        self.block(handler);
+        self.end_scope();
+        self.level_mut().ends_with_return &= try_returns;
+    }
+
+    /// Checks a `match` whose cases are blocks of statements.
+    fn match_statement(&mut self, statement: &Match<Vec<Stmt>>) {
+        self.begin_scope();
+        let subject = self.match_subject(&statement.subject);
+
+        let mut covered = Vec::new();
+        let mut returns = true;
+        for case in &statement.cases {
+            self.case_pattern(case.pattern.as_ref(), &subject, &mut covered);
+            self.level_mut().ends_with_return = false;
+            for stmt in &case.body {
+                self.declaration(stmt);
+            }
+            returns &= self.level().ends_with_return;
+            self.end_scope();
+        }
+
+        self.end_match(&statement.keyword, &subject, &covered);
+        self.end_scope();
+        // A value that no case matches is thrown
+        self.level_mut().ends_with_return = returns;
+    }
+
+    /// Checks the value a `match` is on, returning its type.
+    fn match_subject(&mut self, subject: &Expr) -> Type {
+        let ty = self.expression(subject).ty;
+        if matches!(ty, Type::Option(_) | Type::Result(..) | Type::Any) {
+            return ty;
+        }
+        self.error_at(
+            subject.first.clone(),
+            &format!("Cannot match on a value of type `{}`", ty),
+        );
+        Type::Any
+    }
+
+    /// Checks the pattern of a `case` against the subject. Opens a scope with
+    /// the value inside the variant bound in it.
+    fn case_pattern(
+        &mut self,
+        pattern: Option<&Pattern>,
+        subject: &Type,
+        covered: &mut Vec<Variant>,
+    ) {
+        self.begin_scope();
+        let pattern = match pattern {
+            Some(pattern) => pattern,
+            None => {
+                self.panic_mode = false;
+                return;
+            }
+        };
+
+        let value = match types::pattern(pattern.variant, subject) {
+            Ok(value) => value,
+            Err(message) => {
+                self.error_at(pattern.token.clone(), &message);
+                Type::Any
+            }
+        };
+        if covered.contains(&pattern.variant) {
+            self.error_at(
+                pattern.token.clone(),
+                &format!(
+                    "`{}` is already covered by an earlier case",
+                    pattern.variant
+                ),
+            );
+        }
+        covered.push(pattern.variant);
+
+        if let Some(name) = &pattern.binding {
+            self.add_local(name, false);
+            self.mark_initialized();
+            self.set_variable_type(name, value, false);
+        }
+    }
+
+    /// Checks that the cases of a match cover every variant its subject
+    /// can have, when that is known.
+    fn end_match(&mut self, keyword: &Token, subject: &Type, covered: &[Variant]) {
+        if let Some(variants) = types::variants(subject) {
+            for variant in variants {
+                if !covered.contains(&variant) {
+                    let message = format!("Missing a case for `{}`", variant);
+                    self.error_at(keyword.clone(), &message);
+                }
+            }
+        }
+    }
+
+    fn block(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            self.declaration(stmt);
+        }
+    }
+
+    fn scope_block(&mut self, stmts: &[Stmt]) {
+        self.begin_scope();
+        self.block(stmts);
+        self.end_scope();
+    }
+
+    /// Checks the statements of a block with a value, and returns the type
+    /// of that value: the type of its last statement if that is an
+    /// expression, and void otherwise.
+    fn value_block(&mut self, block: &Block) -> Type {
+        self.begin_scope();
+
+        for stmt in &block.stmts {
+            match stmt {
+                Stmt::Expression(expr) => {
+                    self.expression(expr);
+                    self.panic_mode = false;
+                }
+                Stmt::Broken(broken) if matches!(broken.as_ref(), Stmt::Expression(_)) => {
+                    self.panic_mode = true;
+                    if let Stmt::Expression(expr) = broken.as_ref() {
+                        self.expression(expr);
+                    }
+                    self.panic_mode = false;
+                }
+                stmt => self.declaration(stmt),
+            }
+        }
+
+        let value = match &block.value {
+            Some(value) => {
+                let ty = self.expression(value).ty;
+                // The code will not run, so only the type matters
+                if mem::take(&mut self.panic_mode) {
+                    Type::Any
+                } else {
+                    ty
+                }
+            }
+            None => Type::Void,
+        };
+        self.end_scope();
+        value
+    }
+
+    fn expression(&mut self, expr: &Expr) -> StackType {
+        let ty = match &expr.kind {
+            ExprKind::Literal(value) => Type::of(value),
+            ExprKind::Interpolation(parts) => {
+                for part in parts {
+                    self.expression(part);
+                }
+                Type::Str
+            }
+            ExprKind::Variable(name) => return self.named_variable(name),
+            ExprKind::SelfKw(keyword) => {
+                if self.classes.is_empty() {
+                    self.error_at(keyword.clone(), "Cannot use 'self' outside of a class");
+                    Type::Any
+                } else {
+                    return self.named_variable(keyword);
+                }
+            }
+            ExprKind::Super { keyword, .. } => {
+                match self.classes.last() {
+                    None => self.error_at(keyword.clone(), "Cannot use 'super' outside of a class"),
+                    Some(class) if !class.has_superclass => self.error_at(
+                        keyword.clone(),
+                        "Cannot use 'super' in a class with no superclass",
+                    ),
+                    _ => (),
+                }
+                Type::Any
+            }
+            ExprKind::Assign {
+                name,
+                operator,
+                op,
+                value,
+            } => self.assignment(name, operator, *op, value),
+            ExprKind::Get { object, name } => {
+                let receiver = self.expression(object).ty;
+                self.property(&receiver, name)
+            }
+            ExprKind::Set {
+                object,
+                name,
+                operator,
+                op,
+                value,
+            } => {
+                let receiver = self.expression(object).ty;
+                if receiver == Type::Error {
+                    // Errors have fixed properties, which cannot be assigned
+                    self.property(&receiver, name);
+                    self.error_at(operator.clone(), "Invalid assignment target");
+                    Type::Any
+                } else {
+                    self.property(&receiver, name);
+                    return match op {
+                        Some(op) => {
+                            self.compound_value(operator, *op, StackType::from(Type::Any), value)
+                        }
+                        None => self.expression(value),
+                    };
+                }
+            }
+            ExprKind::Index {
+                collection,
+                bracket,
+                index,
+            } => self.index(collection, bracket, index).1,
+            ExprKind::SetIndex {
+                collection,
+                bracket,
+                index,
+                operator,
+                op,
+                value,
+            } => {
+                let (collection, element) = self.index(collection, bracket, index);
+                let value = match op {
+                    Some(op) => {
+                        self.compound_value(operator, *op, StackType::from(element.clone()), value)
+                    }
+                    None => self.expression(value),
+                };
+                self.constrain(&value, &element);
+                let ty = value.ty;
+                if !self.is_assignable(&element, &ty) {
+                    self.error_at(
+                        expr.last.clone(),
+                        &format!(
+                            "Cannot assign a value of type `{}` to an element of `{}`",
+                            ty, collection
+                        ),
+                    );
+                }
+                ty
+            }
+            ExprKind::Call {
+                callee,
+                args,
+                names,
+                ..
+            } => {
+                let callee = self.expression(callee).ty;
+                let args = args.iter().map(|arg| self.expression(arg)).collect();
+                self.check_call(&callee, args, names, &expr.last)
+            }
+            ExprKind::Unary {
+                operator,
+                op,
+                operand,
+            } => {
+                let operand = self.expression(operand).ty;
+                match types::unary(*op, &operand) {
+                    Ok(ty) => ty,
+                    Err(expected) => {
+                        let message = types::unary_error(&operator.lexeme, &operand, expected);
+                        self.error_at(operator.clone(), &message);
+                        Type::Any
+                    }
+                }
+            }
+            ExprKind::Binary {
+                operator,
+                op,
+                left,
+                right,
+            } => {
+                let left = self.expression(left);
+                let right = self.expression(right);
+                return self.binary_type(operator, *op, left, right);
+            }
+            ExprKind::Logical { left, right, .. } => {
+                // Either operand may be the result
+                let left = self.expression(left).ty;
+                let right = self.expression(right).ty;
+                if left == right {
+                    left
+                } else {
+                    Type::Any
+                }
+            }
+            ExprKind::Contains {
+                operator,
+                item,
+                collection,
+            } => {
+                let item = self.expression(item);
+                let collection = self.expression(collection);
+                if let Err(message) = types::contains(&item.ty, &collection.ty) {
+                    if !self.panic_mode {
+                        self.error_at(operator.clone(), &message);
+                        self.note_inferred(&item);
+                        self.note_inferred(&collection);
+                    }
+                }
+                Type::Bool
+            }
+            ExprKind::List(elements) => {
+                let elements: Vec<Type> = elements
+                    .iter()
+                    .map(|element| self.expression(element).ty)
+                    .collect();
+                let element = elements.into_iter().rev().reduce(Type::join);
+                Type::List(Box::new(element.unwrap_or(Type::Any)))
+            }
+            ExprKind::Map(entries) => {
+                let mut types = Vec::new();
+                for (key, value) in entries {
+                    let key_type = self.expression(key).ty;
+                    if let Err(message) = types::check_key(&key_type) {
+                        self.error_at(key.first.clone(), &message);
+                    }
+                    types.push((key_type, self.expression(value).ty));
+                }
+                let entry = types
+                    .into_iter()
+                    .rev()
+                    .reduce(|(k, v), (key, value)| (Type::join(key, k), Type::join(value, v)));
+                let (key, value) = entry.unwrap_or((Type::Any, Type::Any));
+                Type::Map(Box::new(key), Box::new(value))
+            }
+            ExprKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expression(condition);
+                let then_type = self.value_block(then_branch);
+                let else_type = match else_branch {
+                    Some(else_branch) => self.value_block(else_branch),
+                    None => Type::Void,
+                };
+
+                let no_superclass = |_: &str| None;
+                if then_type.accepts(&else_type, &no_superclass)
+                    && else_type.accepts(&then_type, &no_superclass)
+                {
+                    then_type.join(else_type)
+                } else {
+                    self.error_at(
+                        expr.last.clone(),
+                        &format!(
+                            "The branches of an if expression must have the same type, found `{}` and `{}`",
+                            then_type, else_type
+                        ),
+                    );
+                    Type::Any
+                }
+            }
+            ExprKind::Do(block) => self.value_block(block),
+            ExprKind::Match(expression) => self.match_expression(expression),
+            ExprKind::Wrap { variant, value } => {
+                let value = self.expression(value).ty;
+                types::wrap(*variant, value)
+            }
+            ExprKind::None => types::wrap(Variant::None, Type::Any),
+            ExprKind::Propagate { operator, value } => {
+                let value = self.expression(value).ty;
+                match self.level().function_type {
+                    FunctionType::Script => {
+                        self.error_at(operator.clone(), "Cannot use '?' in top-level code")
+                    }
+                    FunctionType::Initializer => {
+                        self.error_at(operator.clone(), "Cannot use '?' in an initializer")
+                    }
+                    _ => (),
+                }
+
+                let (unwrapped, returned) = match types::propagate(&value) {
+                    Ok(types) => types,
+                    Err(message) => {
+                        self.error_at(operator.clone(), &message);
+                        (Type::Any, Type::Any)
+                    }
+                };
+                // Only a conditional exit, so it does not count as a return
+                self.check_return(operator, operator, StackType::from(returned));
+                unwrapped
+            }
+            ExprKind::Lambda(fun) => self.function(fun),
+            ExprKind::Error => Type::Any,
+        };
+        StackType::from(ty)
+    }
+
+    /// Checks a `match` whose cases are blocks with a value, like
+    /// `value_block`, and returns the type of that value.
+    fn match_expression(&mut self, expression: &Match<Block>) -> Type {
+        self.begin_scope();
+        let subject = self.match_subject(&expression.subject);
+
+        let mut covered = Vec::new();
+        let mut value: Option<Type> = None;
+        for case in &expression.cases {
+            self.case_pattern(case.pattern.as_ref(), &subject, &mut covered);
+            let ty = self.value_block(&case.body);
+            self.end_scope();
+
+            let no_superclass = |_: &str| None;
+            value = match value {
+                None => Some(ty),
+                Some(value)
+                    if value.accepts(&ty, &no_superclass) && ty.accepts(&value, &no_superclass) =>
+                {
+                    Some(value.join(ty))
+                }
+                Some(value) => {
+                    self.error_at(
+                        case.keyword.clone(),
+                        &format!(
+                            "The cases of a match expression must have the same type, found `{}` and `{}`",
+                            value, ty
+                        ),
+                    );
+                    Some(Type::Any)
+                }
+            };
+        }
+
+        self.end_match(&expression.keyword, &subject, &covered);
+        self.end_scope();
+        value.unwrap_or(Type::Void)
+    }
+
+    fn named_variable(&mut self, name: &Token) -> StackType {
+        // A local is only on the stack once its initializer has run
+        let uninitialized = self.levels.iter().rev().find_map(|level| {
+            level
+                .locals
+                .iter()
+                .rev()
+                .find(|local| local.name.lexeme == name.lexeme)
+                .map(|local| local.depth.is_none())
+        });
+        if uninitialized == Some(true) {
+            self.error_at(
+                name.clone(),
+                "Cannot read local variable in its own initializer",
+            );
+        }
+
+        StackType {
+            ty: self.variable_type(name),
+            variable: Some(name.clone()),
+        }
+    }
+
+    fn assignment(
+        &mut self,
+        name: &Token,
+        operator: &Token,
+        op: Option<OpCode>,
+        value: &Expr,
+    ) -> Type {
+        let current = self.named_variable(name);
+        self.check_mutable(name);
+
+        let (ty, actual) = match op {
+            None => (current.ty, self.expression(value)),
+            Some(op) => {
+                let actual = self.compound_value(operator, op, current, value);
+                (self.variable_type(name), actual)
+            }
+        };
+        // A value whose type has an error in it is not what the variable is
+        // meant to hold
+        if let Some(declaration) = self.inferred_declaration(name).filter(|_| !self.panic_mode) {
+            let assigned = match self.assigned.remove(&declaration) {
+                Some(assigned) => assigned.join(actual.ty.clone()),
+                None => actual.ty.clone(),
+            };
+            self.assigned.insert(declaration, assigned);
+        }
+        self.check_assignment(name, &value.last, &ty, &actual);
+        actual.ty
+    }
+
+    /// Checks reading the property `name` of a value of type `receiver`.
+    fn property(&mut self, receiver: &Type, name: &Token) -> Type {
+        if *receiver == Type::Error {
+            return match types::error_property(&name.lexeme) {
+                Ok(ty) => ty,
+                Err(message) => {
+                    self.error_at(name.clone(), &message);
+                    Type::Any
+                }
+            };
+        }
+        if !matches!(receiver, Type::Instance(_) | Type::Any) {
+            self.error_at(
+                name.clone(),
+                &format!("Only instances have properties, found `{}`", receiver),
+            );
+        }
+        Type::Any
+    }
+
+    /// Checks indexing into a collection, returning the type of the
+    /// collection and of its elements.
+    fn index(&mut self, collection: &Expr, bracket: &Token, index: &Expr) -> (Type, Type) {
+        let collection = self.expression(collection).ty;
+        let index = self.expression(index);
+        let expected = match &collection {
+            Type::List(_) => Type::Int,
+            Type::Map(key, _) => key.as_ref().clone(),
+            _ => Type::Any,
+        };
+        self.constrain(&index, &expected);
+        let index = index.ty;
+
+        let element = match types::index(&collection, &index) {
+            Ok(element) => element,
+            Err(message) => {
+                self.error_at(bracket.clone(), &message);
+                Type::Any
+            }
+        };
+        (collection, element)
+    }
+
+    /// Checks the operand types of a binary operator, returning the type of
+    /// its result.
+    fn binary_type(
+        &mut self,
+        operator: &Token,
+        checked_op: OpCode,
+        left: StackType,
+        right: StackType,
+    ) -> StackType {
+        // An operand of unknown type has to suit the operator along with the
+        // other operand
+        if checked_op != OpCode::Equal {
+            if let Some(ty) = types::binary_operand(checked_op, &right.ty) {
+                self.constrain(&left, &ty);
+            }
+            if let Some(ty) = types::binary_operand(checked_op, &left.ty) {
+                self.constrain(&right, &ty);
+            }
+        }
+
+        match types::binary(checked_op, &left.ty, &right.ty) {
+            Ok(ty) => StackType::from(ty),
+            Err(expected) => {
+                let message = types::binary_error(&operator.lexeme, &left.ty, &right.ty, expected);
+                if !self.panic_mode {
+                    self.error_at(operator.clone(), &message);
+                    self.note_inferred(&left);
+                    self.note_inferred(&right);
+                }
+                StackType::from(Type::Any)
+            }
+        }
+    }
+
+    /// Checks the right-hand side of a compound assignment and the operator
+    /// applied to it and the `current` value of the target, returning the
+    /// type of the new value. `x++` and `x--` are short for `x += 1` and
+    /// `x -= 1`.
+    fn compound_value(
+        &mut self,
+        operator: &Token,
+        op: OpCode,
+        current: StackType,
+        value: &Expr,
+    ) -> StackType {
+        if matches!(operator.id, TokenType::PlusPlus | TokenType::MinusMinus) {
+            return self.increment(operator, op, current);
+        }
+        let value = self.expression(value);
+        self.binary_type(operator, op, current, value)
+    }
+
+    fn increment(&mut self, operator: &Token, op: OpCode, current: StackType) -> StackType {
+        // Only numbers can be incremented, although `+` also joins strings
+        if let Err(expected) = types::unary(OpCode::Negate, &current.ty) {
+            let message = types::unary_error(&operator.lexeme, &current.ty, expected);
+            if !self.panic_mode {
+                self.error_at(operator.clone(), &message);
+                self.note_inferred(&current);
+            }
+            return StackType::from(Type::Any);
+        }
+        self.binary_type(operator, op, current, StackType::from(Type::Int))
+    }
+
+    /// Checks a call, reporting its errors at `at`, and returns the type of
+    /// its result.
+    fn check_call(
+        &mut self,
+        callee: &Type,
+        args: Vec<StackType>,
+        names: &[String],
+        at: &Token,
+    ) -> Type {
+        match callee {
+            Type::Fun(Some(signature)) => {
+                self.check_arguments(signature, args, names, at);
+                signature.ret.clone()
+            }
+            Type::Class(name) => {
+                match self.class_init(name) {
+                    Some(Some(init)) => self.check_arguments(&init, args, names, at),
+                    Some(None) => {
+                        self.check_arguments(&Signature::new(vec![], Type::Void), args, names, at)
+                    }
+                    None => (),
+                }
+                Type::Instance(name.clone())
+            }
+            Type::Fun(None) | Type::Any => Type::Any,
+            other => {
+                self.error_at(
+                    at.clone(),
+                    &format!("Can only call functions and classes, found `{}`", other),
+                );
+                Type::Any
+            }
+        }
+    }
+
+    fn check_arguments(
+        &mut self,
+        signature: &Signature,
+        args: Vec<StackType>,
+        names: &[String],
+        at: &Token,
+    ) {
+        // Signatures from type annotations do not name their parameters, so
+        // named arguments can only be matched up at runtime
+        let unnamed;
+        let param_names = if signature.names.len() == signature.params.len() {
+            &signature.names
+        } else if names.is_empty() {
+            unnamed = vec![String::new(); signature.params.len()];
+            &unnamed
+        } else {
+            return;
+        };
+
+        let arguments = match types::bind_arguments(
+            param_names,
+            signature.required,
+            signature.rest.is_some(),
+            args,
+            names,
+        ) {
+            Ok(arguments) => arguments,
+            Err(message) => return self.error_at(at.clone(), &message),
+        };
+
+        let rest = signature.rest.clone().unwrap_or(Type::Any);
+        let params = signature.params.iter().zip(arguments.params);
+        let extra = arguments.rest.into_iter().map(|arg| (&rest, Some(arg)));
+        for (i, (param, arg)) in params.chain(extra).enumerate() {
+            let arg = match arg {
+                Some(arg) => arg,
+                None => continue,
+            };
+            self.constrain(&arg, param);
+            let ty = arg.ty.clone();
+            if !self.is_assignable(param, &ty) {
+                if !self.panic_mode {
+                    let argument = match param_names.get(i) {
+                        Some(name) if names.contains(name) => format!("`{}`", name),
+                        _ => (i + 1).to_string(),
+                    };
+                    self.error_at(
+                        at.clone(),
+                        &format!(
+                            "Argument {} expects a value of type `{}`, but found `{}`",
+                            argument, param, ty
+                        ),
+                    );
+                    if let Some(Some(inferred_at)) = signature.inferred_at.get(i) {
+                        self.note_at(
+                            inferred_at.clone(),
+                            &format!("`{}` was inferred to be `{}` here", param_names[i], param),
+                        );
+                    }
+                    self.note_inferred(&arg);
+                }
+                return;
+            }
+        }
+    }
+
+    /// The initializer a call to the class runs: `Some(None)` when the class
+    /// and its superclasses have none, and `None` when it cannot be known.
+    fn class_init(&self, name: &str) -> Option<Option<Rc<Signature>>> {
+        let mut class = self.class_types.get(name)?;
+        loop {
+            if class.init.is_some() {
+                return Some(class.init.clone());
+            }
+            if class.dynamic_superclass {
+                return None;
+            }
+            match &class.superclass {
+                Some(superclass) => class = self.class_types.get(superclass)?,
+                None => return Some(None),
+            }
+        }
+    }
+
+    fn mark_initialized(&mut self) {
+        if self.level().scope_depth > 0 {
+            self.level_mut().locals.last_mut().unwrap().depth = Some(self.level().scope_depth);
+        }
+    }
+
+    fn declare_variable(&mut self, name: &Token, mutable: bool) {
+        if self.level().scope_depth == 0 {
+            if self.levels.len() == 1 {
+                *self
+                    .global_declarations
+                    .entry(name.lexeme.clone())
+                    .or_default() += 1;
+            }
+            return;
+        }
+
+        if self.search_locals(name) {
+            self.error_at(name.clone(), "Cannot redeclare variable in this scope");
+        }
+        self.add_local(name, mutable);
+    }
+
+    fn search_locals(&self, name: &Token) -> bool {
+        for local in self.level().locals.iter().rev() {
+            if local.depth.is_some() && local.depth.unwrap() < self.level().scope_depth {
+                return false;
+            }
+            if local.name.lexeme == name.lexeme {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn add_local(&mut self, name: &Token, mutable: bool) {
+        self.level_mut()
+            .locals
+            .push(Local::new(name.clone(), mutable));
+    }
+
+    /// Finds the local that `name` refers to, in this function or one that
+    /// encloses it.
+    fn find_local(&self, name: &Token) -> Option<&Local> {
+        self.levels.iter().rev().find_map(|level| {
+            level
+                .locals
+                .iter()
+                .rev()
+                .find(|local| local.name.lexeme == name.lexeme)
+        })
+    }
+
+    /// Finds the `let` declaration that an assignment to `name` would write
+    /// to. Functions can run after a `let` that comes later in the script,
+    /// so inside them the last declaration of a global counts too.
+    fn immutable_declaration(&self, name: &Token) -> Option<Token> {
+        match self.find_local(name) {
+            Some(local) => (!local.mutable).then(|| local.name.clone()),
+            None => self
+                .immutable_globals
+                .get(&name.lexeme)
+                .or_else(|| {
+                    (self.levels.len() > 1)
+                        .then(|| self.hints.immutable.get(&name.lexeme))
+                        .flatten()
+                })
+                .cloned(),
+        }
+    }
+
+    /// Reports an assignment to `name` if it was declared with `let`.
+    fn check_mutable(&mut self, name: &Token) {
+        if let Some(declaration) = self.immutable_declaration(name) {
+            self.error_immutable(name.clone(), declaration);
+        }
+    }
+
+    /// The type of the variable `name`. Inside a function, a global that is
+    /// declared once has its type even before its declaration, as the
+    /// function can only run after it.
+    fn variable_type(&self, name: &Token) -> Type {
+        if let Some(local) = self.find_local(name) {
+            return local.ty.clone();
+        }
+        let global = match self.global_types.get(&name.lexeme) {
+            Some(ty) => Some(ty),
+            None if self.levels.len() > 1 => self.hints.globals.get(&name.lexeme),
+            None => None,
+        };
+        global.cloned().unwrap_or(Type::Any)
+    }
+
+    fn set_variable_type(&mut self, name: &Token, ty: Type, annotated: bool) {
+        let inferred_at = (!annotated).then(|| name.clone());
+        // An unannotated variable can hold every value assigned to it
+        let ty = match self.hints.assigned.get(&(name.line, name.column)) {
+            Some(assigned) if !annotated => ty.join(assigned.clone()),
+            _ => ty,
+        };
+
+        if self.level().scope_depth > 0 {
+            let local = self.level_mut().locals.last_mut().unwrap();
+            local.ty = ty;
+            local.annotated = annotated;
+            local.inferred_at = inferred_at;
+        } else {
+            self.global_types.insert(name.lexeme.clone(), ty);
+            match inferred_at {
+                Some(at) => self.global_inferred.insert(name.lexeme.clone(), at),
+                None => self.global_inferred.remove(&name.lexeme),
+            };
+        }
+    }
+
+    /// Records that a use of `value` requires a value of type `expected`.
+    /// A parameter without an annotation is inferred to have the least
+    /// restrictive type that all of its uses allow, and stays `any` when
+    /// they conflict.
+    fn constrain(&mut self, value: &StackType, expected: &Type) {
+        let name = match &value.variable {
+            Some(name) if value.ty == Type::Any && *expected != Type::Any => name,
+            _ => return,
+        };
+
+        for level in self.levels.iter_mut().rev() {
+            if let Some(local) = level
+                .locals
+                .iter_mut()
+                .rev()
+                .find(|local| local.name.lexeme == name.lexeme)
+            {
+                if !local.inferred {
+                    return;
+                }
+                let required = local
+                    .required
+                    .clone()
+                    .and_then(|ty| ty.meet(expected.clone()));
+                // Point at the use that narrowed the type last
+                if required.is_some() && required != local.required {
+                    local.inferred_at = Some(name.clone());
+                }
+                local.required = required;
+                return;
+            }
+        }
+    }
+
+    /// The position of the declaration of `name`, if it is a variable whose
+    /// type is inferred from the values assigned to it.
+    fn inferred_declaration(&self, name: &Token) -> Option<(usize, usize)> {
+        let declaration = match self.find_local(name) {
+            Some(local) if local.annotated || local.inferred => return None,
+            Some(local) => Some(&local.name),
+            None => self.global_inferred.get(&name.lexeme).or_else(|| {
+                (self.levels.len() > 1)
+                    .then(|| self.hints.inferred.get(&name.lexeme))
+                    .flatten()
+            }),
+        };
+        declaration.map(|name| (name.line, name.column))
+    }
+
+    /// Points at where the type of a value read from a variable was
+    /// inferred, when that is the reason it does not fit.
+    fn note_inferred(&mut self, value: &StackType) {
+        let name = match &value.variable {
+            Some(name) => name,
+            None => return,
+        };
+        let at = match self.find_local(name) {
+            Some(local) => local.inferred_at.clone(),
+            None => self.global_inferred.get(&name.lexeme).cloned(),
+        };
+        if let Some(at) = at {
+            self.note_at(
+                at,
+                &format!("`{}` was inferred to be `{}` here", name.lexeme, value.ty),
+            );
+        }
+    }
+
+    /// Resolves a type annotation, in which a class name stands for its
+    /// instances.
+    fn resolve_type(&mut self, annotation: &TypeExpr) -> Type {
+        match annotation {
+            TypeExpr::Builtin(ty) => ty.clone(),
+            TypeExpr::Class(name) if self.class_types.contains_key(&name.lexeme) => {
+                Type::Instance(name.lexeme.clone())
+            }
+            TypeExpr::Class(name) => {
+                self.error_at(name.clone(), &format!("Unknown type `{}`", name.lexeme));
+                Type::Any
+            }
+            TypeExpr::Fun(params, ret) => {
+                let params = params
+                    .iter()
+                    .map(|param| self.resolve_type(param))
+                    .collect();
+                let ret = self.resolve_type(ret);
+                Type::Fun(Some(Rc::new(Signature::new(params, ret))))
+            }
+            TypeExpr::List(element) => Type::List(Box::new(self.resolve_type(element))),
+            TypeExpr::Map {
+                key,
+                key_end,
+                value,
+            } => {
+                let builtin = matches!(**key, TypeExpr::Builtin(_));
+                let key = self.resolve_type(key);
+                // The parser has already reported built-in key types
+                if let (false, Err(message)) = (builtin, types::check_key(&key)) {
+                    self.error_at(key_end.clone(), &message);
+                }
+                let value = self.resolve_type(value);
+                Type::Map(Box::new(key), Box::new(value))
+            }
+            TypeExpr::Option(value) => Type::Option(Box::new(self.resolve_type(value))),
+            TypeExpr::Result(value, error) => {
+                let value = self.resolve_type(value);
+                let error = self.resolve_type(error);
+                Type::Result(Box::new(value), Box::new(error))
+            }
+        }
+    }
+
+    fn is_assignable(&self, expected: &Type, actual: &Type) -> bool {
+        expected.accepts(actual, &|name| {
+            self.class_types
+                .get(name)
+                .and_then(|class| class.superclass.clone())
+        })
+    }
+
+    /// Checks assigning `actual` to the variable `name`, reporting a
+    /// mismatch at `at`.
+    fn check_assignment(&mut self, name: &Token, at: &Token, expected: &Type, actual: &StackType) {
+        self.constrain(actual, expected);
+        if self.is_assignable(expected, &actual.ty) || self.panic_mode {
+            return;
+        }
+
+        self.error_at(
+            at.clone(),
+            &format!(
+                "Cannot assign a value of type `{}` to `{}` of type `{}`",
+                actual.ty, name.lexeme, expected
+            ),
+        );
+        self.note_inferred(actual);
+    }
+
+    fn error_at(&mut self, token: Token, msg: &str) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            message: msg.to_string(),
+            line: token.line,
+            column: token.column,
+            lexeme: (token.id != TokenType::Eof).then_some(token.lexeme),
+            span: token.span,
+            labels: Vec::new(),
+            notes: Vec::new(),
+        });
+    }
+
+    fn error_immutable(&mut self, assignment: Token, declaration: Token) {
+        if self.panic_mode {
+            return;
+        }
+        let name = assignment.lexeme.clone();
+        self.error_at(
+            assignment,
+            &format!("Cannot assign twice to immutable variable `{}`", name),
+        );
+        self.note_at(declaration, "Declared with 'let' here");
+        self.note("Declare it with 'var' to allow assigning to it");
+    }
+
+    /// Points at another token that explains the error just reported.
+    fn note_at(&mut self, token: Token, msg: &str) {
+        if let Some(diagnostic) = self.diagnostics.last_mut() {
+            diagnostic.labels.push(Label {
+                message: msg.to_string(),
+                line: token.line,
+                column: token.column,
+                lexeme: token.lexeme,
+                span: token.span,
+            });
+        }
+    }
+
+    /// Adds a remark to the error just reported.
+    fn note(&mut self, msg: &str) {
+        if let Some(diagnostic) = self.diagnostics.last_mut() {
+            diagnostic.notes.push(msg.to_string());
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.level_mut().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.level_mut().scope_depth -= 1;
+
+        let scope_depth = self.level().scope_depth;
+        while let Some(local) = self.level().locals.last() {
+            if local.depth.is_none_or(|depth| depth <= scope_depth) {
+                break;
+            }
+            self.level_mut().locals.pop();
+        }
+    }
+}
+
+/// Checks the types in a parsed program and the uses of its variables,
+/// returning the errors found.
+pub fn check(program: &[Stmt]) -> Vec<Diagnostic> {
+    let mut checker = Checker::new(Hints::default());
+    checker.check(program);
+    for _ in 1..MAX_PASSES {
+        let hints = checker.hints();
+        if hints.same_types(&checker.hints) {
+            break;
+        }
+        checker = Checker::new(hints);
+        checker.check(program);
+    }
+    checker.diagnostics
+}
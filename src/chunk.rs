@@ -1,13 +1,56 @@
-use crate::objects::Function;
+use crate::{
+    bigint::BigInt,
+    objects::{
+        BoundMethod, Class, Closure, Exception, Function, Instance, Map, NativeFunction, ValueIter,
+    },
+};
+use std::{cell::RefCell, rc::Rc};
 
 #[derive(Clone, PartialEq)]
 pub enum Value {
     Void,
     Bool(bool),
     Int(isize),
+    /// An integer outside of the range of `isize`. Arithmetic on `Int`s that
+    /// overflows produces one of these, so both have the type `int`.
+    BigInt(Rc<BigInt>),
     Float(f64),
     Str(String),
-    Fun(Function),
+    Fun(Rc<Function>),
+    Closure(Rc<Closure>),
+    Native(Rc<NativeFunction>),
+    Class(Rc<RefCell<Class>>),
+    Instance(Rc<RefCell<Instance>>),
+    BoundMethod(Rc<BoundMethod>),
+    List(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<Map>>),
+    /// The integers from the start up to, but not including, the end.
+    Range(isize, isize),
+    Iterator(Rc<RefCell<ValueIter>>),
+    Error(Rc<Exception>),
+    /// A value of an `option` or `result` type. `None` holds `Void`.
+    Variant(Variant, Rc<Value>),
+}
+
+/// The constructors of the built-in `option` and `result` types.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Variant {
+    Some,
+    None,
+    Ok,
+    Err,
+}
+
+impl Display for Variant {
+    fn fmt(&self, format: &mut Formatter<'_>) -> Result {
+        let name = match self {
+            Variant::Some => "Some",
+            Variant::None => "None",
+            Variant::Ok => "Ok",
+            Variant::Err => "Err",
+        };
+        write!(format, "{}", name)
+    }
 }
 
 use std::fmt::{Display, Formatter, Result};
@@ -17,6 +60,7 @@ impl Display for Value {
         match self {
             Bool(value) => write!(format, "{}", value),
             Int(value) => write!(format, "{}", value),
+            BigInt(value) => write!(format, "{}", value),
             Float(value) => {
                 if value.floor() == *value {
                     write!(format, "{}.0", value)
@@ -26,20 +70,89 @@ impl Display for Value {
             }
             Str(value) => write!(format, "{}", value),
             Fun(value) => write!(format, "{}", value),
+            Closure(value) => write!(format, "{}", value),
+            Native(value) => write!(format, "{}", value),
+            Class(value) => write!(format, "{}", value.borrow()),
+            Instance(value) => write!(format, "{}", value.borrow()),
+            BoundMethod(value) => write!(format, "{}", value),
+            List(list) => {
+                write!(format, "[")?;
+                for (i, item) in list.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(format, ", ")?;
+                    }
+                    write!(format, "{}", item.repr())?;
+                }
+                write!(format, "]")
+            }
+            Map(map) => {
+                write!(format, "{{")?;
+                for (i, (key, value)) in map.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(format, ", ")?;
+                    }
+                    write!(format, "{}: {}", key.to_value().repr(), value.repr())?;
+                }
+                write!(format, "}}")
+            }
+            Range(start, end) => write!(format, "{}..{}", start, end),
+            Iterator(_) => write!(format, "<iterator>"),
+            Error(error) => write!(format, "{}", error),
+            Variant(self::Variant::None, _) => write!(format, "None"),
+            Variant(variant, value) => write!(format, "{}({})", variant, value.repr()),
             Void => write!(format, "void"),
         }
     }
 }
 
-pub fn type_as_str<'a>(value: Value) -> &'a str {
-    use Value::*;
-    match value {
-        Bool(_) => "bool",
-        Int(_) => "int",
-        Float(_) => "float",
-        Str(_) => "str",
-        Fun(_) => "fun",
-        Void => "void",
+impl From<BigInt> for Value {
+    /// Keeps integers that fit in an `isize` as `Value::Int`.
+    fn from(value: BigInt) -> Self {
+        match value.to_isize() {
+            Some(value) => Value::Int(value),
+            None => Value::BigInt(Rc::new(value)),
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<isize> for Value {
+    fn from(value: isize) -> Self {
+        Value::Int(value)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Float(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Str(value.to_string())
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(values: Vec<Value>) -> Self {
+        Value::List(Rc::new(RefCell::new(values)))
+    }
+}
+
+impl Value {
+    /// Formats the value as it is written in source code, so that strings
+    /// inside collections are quoted.
+    pub fn repr(&self) -> String {
+        match self {
+            Value::Str(value) => format!("'{}'", value),
+            value => value.to_string(),
+        }
     }
 }
 
@@ -50,6 +163,15 @@ pub enum OpCode {
     Subtract,
     Multiply,
     Divide,
+    FloorDivide,
+    Modulo,
+    Power,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
+    BitNot,
     Negate,
     Not,
     Return,
@@ -58,6 +180,8 @@ pub enum OpCode {
     Less,
     Print,
     Pop,
+    Duplicate(usize),
+    EndBlock(usize),
     Jump(usize),
     JumpIfFalse(usize),
     JumpBack(usize),
@@ -67,11 +191,40 @@ pub enum OpCode {
     GetLocal(usize),
     SetLocal(usize),
     Call(usize),
+    CallNamed(usize, usize),
+    Closure(usize),
+    GetUpvalue(usize),
+    SetUpvalue(usize),
+    CloseUpvalue,
+    Class(usize),
+    GetProperty(usize),
+    SetProperty(usize),
+    Method(usize),
+    Inherit,
+    GetSuper(usize),
+    BuildList(usize),
+    BuildMap(usize),
+    GetIndex,
+    SetIndex,
+    In,
+    Range,
+    Iter,
+    ForIter(usize),
+    Interpolate(usize),
+    Try(usize),
+    EndTry,
+    Throw,
+    Wrap(Variant),
+    Match(Variant, usize),
+    NoMatch,
+    Propagate,
 }
 
 #[derive(Clone, PartialEq)]
 pub struct Chunk {
     pub lines: Vec<usize>,
+    /// The column of the token each instruction was compiled from.
+    pub columns: Vec<usize>,
     pub constants: Vec<Value>,
     pub code: Vec<OpCode>,
 }
@@ -80,14 +233,16 @@ impl Chunk {
     pub fn new() -> Self {
         Self {
             lines: Vec::new(),
+            columns: Vec::new(),
             constants: Vec::new(),
             code: Vec::new(),
         }
     }
 
-    pub fn write(&mut self, byte: OpCode, line: usize) {
+    pub fn write(&mut self, byte: OpCode, line: usize, column: usize) {
         self.code.push(byte);
         self.lines.push(line);
+        self.columns.push(column);
     }
 
     pub fn add_constant(&mut self, value: Value) -> OpCode {
@@ -95,7 +250,7 @@ impl Chunk {
         OpCode::Constant(self.constants.len() - 1)
     }
 
-    #[cfg(debug_assertions)]
+    #[cfg(feature = "trace")]
     pub fn disassemble(&self, name: &str) {
         println!("== {} ==", name);
         for (i, instruction) in self.code.iter().enumerate() {
@@ -103,17 +258,17 @@ impl Chunk {
         }
     }
 
-    #[cfg(debug_assertions)]
+    #[cfg(feature = "trace")]
     fn disassemble_constant(&self, name: &str, index: usize) {
         println!("{:<16} {:>4} ({})", name, index, self.constants[index]);
     }
 
-    #[cfg(debug_assertions)]
+    #[cfg(feature = "trace")]
     fn disassemble_large(&self, name: &str, index: usize) {
         println!("{:<16} {:>4}", name, index);
     }
 
-    #[cfg(debug_assertions)]
+    #[cfg(feature = "trace")]
     pub fn disassemble_op(&self, instruction: &OpCode, i: usize) {
         print!("{:04} ", i);
         if i > 0 && self.lines[i] == self.lines[i - 1] {
@@ -129,6 +284,15 @@ impl Chunk {
             Subtract => println!("SUBTRACT"),
             Multiply => println!("MULTIPLY"),
             Divide => println!("DIVIDE"),
+            FloorDivide => println!("FLOOR_DIVIDE"),
+            Modulo => println!("MODULO"),
+            Power => println!("POWER"),
+            BitAnd => println!("BIT_AND"),
+            BitOr => println!("BIT_OR"),
+            BitXor => println!("BIT_XOR"),
+            ShiftLeft => println!("SHIFT_LEFT"),
+            ShiftRight => println!("SHIFT_RIGHT"),
+            BitNot => println!("BIT_NOT"),
             Negate => println!("NEGATE"),
             Not => println!("NOT"),
             Return => println!("RETURN"),
@@ -137,6 +301,8 @@ impl Chunk {
             Less => println!("LESS"),
             Print => println!("PRINT"),
             Pop => println!("POP"),
+            Duplicate(len) => self.disassemble_large("DUPLICATE", *len),
+            EndBlock(len) => self.disassemble_large("END_BLOCK", *len),
             Jump(index) => self.disassemble_large("JUMP", *index + 1),
             JumpIfFalse(index) => self.disassemble_large("JUMP_IF_FALSE", *index + 1),
             JumpBack(index) => self.disassemble_large("JUMP_BACK", *index - 1),
@@ -146,6 +312,48 @@ impl Chunk {
             GetLocal(index) => self.disassemble_large("GET_LOCAL", *index),
             SetLocal(index) => self.disassemble_large("SET_LOCAL", *index),
             Call(index) => self.disassemble_large("CALL", *index),
+            CallNamed(arg_len, index) => {
+                println!(
+                    "{:<16} {:>4} {:>4} ({})",
+                    "CALL_NAMED", arg_len, index, self.constants[*index]
+                );
+            }
+            Closure(index) => {
+                self.disassemble_constant("CLOSURE", *index);
+                if let Value::Fun(function) = &self.constants[*index] {
+                    for upvalue in &function.upvalues {
+                        let kind = if upvalue.is_local { "local" } else { "upvalue" };
+                        println!("          |      {} {}", kind, upvalue.index);
+                    }
+                }
+            }
+            GetUpvalue(index) => self.disassemble_large("GET_UPVALUE", *index),
+            SetUpvalue(index) => self.disassemble_large("SET_UPVALUE", *index),
+            CloseUpvalue => println!("CLOSE_UPVALUE"),
+            Class(index) => self.disassemble_constant("CLASS", *index),
+            GetProperty(index) => self.disassemble_constant("GET_PROPERTY", *index),
+            SetProperty(index) => self.disassemble_constant("SET_PROPERTY", *index),
+            Method(index) => self.disassemble_constant("METHOD", *index),
+            Inherit => println!("INHERIT"),
+            GetSuper(index) => self.disassemble_constant("GET_SUPER", *index),
+            BuildList(len) => self.disassemble_large("BUILD_LIST", *len),
+            BuildMap(len) => self.disassemble_large("BUILD_MAP", *len),
+            GetIndex => println!("GET_INDEX"),
+            SetIndex => println!("SET_INDEX"),
+            In => println!("IN"),
+            Range => println!("RANGE"),
+            Iter => println!("ITER"),
+            ForIter(index) => self.disassemble_large("FOR_ITER", *index + 1),
+            Interpolate(len) => self.disassemble_large("INTERPOLATE", *len),
+            Try(index) => self.disassemble_large("TRY", *index + 1),
+            EndTry => println!("END_TRY"),
+            Throw => println!("THROW"),
+            Wrap(variant) => println!("{:<16} {:>4}", "WRAP", variant),
+            Match(variant, index) => {
+                println!("{:<16} {:>4} {}", "MATCH", variant, *index + 1)
+            }
+            NoMatch => println!("NO_MATCH"),
+            Propagate => println!("PROPAGATE"),
         }
     }
 }
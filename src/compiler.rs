@@ -1,72 +1,24 @@
 use crate::{
-    chunk::{Chunk, OpCode, Value},
-    lexer::Lexer,
-    objects::{Function, FunctionType},
+    ast::{Block, Class, Expr, ExprKind, Fun, Match, Pattern, Stmt},
+    checker,
+    chunk::{Chunk, OpCode, Value, Variant},
+    objects::{Function, FunctionType, UpvalueIndex},
+    parser,
     result::LangError,
     token::{Token, TokenType},
 };
-use std::collections::HashMap;
+use std::{cell::RefCell, rc::Rc};
 
 const JUMP_PLACEHOLDER: usize = usize::MAX;
 
-#[derive(PartialEq, PartialOrd)]
-enum Precedence {
-    None,
-    Assignment,
-    Or,
-    And,
-    Equality,
-    Comparison,
-    Term,
-    Factor,
-    Unary,
-    Call,
-    Primary,
-}
-
-impl Precedence {
-    fn next(&self) -> Self {
-        use Precedence::*;
-        match self {
-            None => Assignment,
-            Assignment => Or,
-            Or => And,
-            And => Equality,
-            Equality => Comparison,
-            Comparison => Term,
-            Term => Factor,
-            Factor => Unary,
-            Unary => Call,
-            Call => Primary,
-            Primary => panic!("No rule higher than Primary"),
-        }
-    }
-}
-
-type ParseFn = Option<fn(&mut Compiler, can_assign: bool) -> ()>;
-
-struct ParseRule {
-    prefix: ParseFn,
-    infix: ParseFn,
-    precedence: Precedence,
-}
-
+#[derive(Clone)]
 struct Local {
-    name: Token,
+    name: String,
     depth: Option<usize>,
-}
-
-impl Default for Local {
-    fn default() -> Local {
-        Local {
-            name: Token {
-                id: TokenType::Error,
-                lexeme: String::new(),
-                line: 0,
-            },
-            depth: Some(0),
-        }
-    }
+    /// Where the local lives in the frame. Locals declared inside an
+    /// expression sit above the temporaries of that expression.
+    slot: usize,
+    is_captured: bool,
 }
 
 struct Level {
@@ -74,110 +26,72 @@ struct Level {
     function_type: FunctionType,
     locals: Vec<Local>,
     scope_depth: usize,
+    loops: Vec<Loop>,
+    /// How many `try` blocks the code being compiled is inside of.
+    tries: usize,
+    /// How many operands of the expressions being compiled are on the stack
+    /// above the locals.
+    temporaries: usize,
 }
 
 impl Level {
     fn new(function_type: FunctionType) -> Self {
+        let receiver = match function_type {
+            FunctionType::Method | FunctionType::Initializer => "self",
+            _ => "",
+        };
+
         Level {
             function: Function::new(),
             function_type,
-            locals: vec![Local::default()],
+            locals: vec![Local {
+                name: String::from(receiver),
+                depth: Some(0),
+                slot: 0,
+                is_captured: false,
+            }],
             scope_depth: 0,
+            loops: Vec::new(),
+            tries: 0,
+            temporaries: 0,
         }
     }
 }
 
+/// A loop being compiled. `continue` jumps back to `start`, and the jumps
+/// emitted by `break` are patched once the end of the loop is known. Both
+/// first discard what the body has on the stack above `height`, and leave
+/// the `try` blocks entered after `tries`.
+struct Loop {
+    start: usize,
+    height: usize,
+    tries: usize,
+    breaks: Vec<usize>,
+}
+
+/// Emits the bytecode of a program that the checker has found no errors in.
 struct Compiler {
     levels: Vec<Level>,
-    lexer: Lexer,
-    curr: Token,
-    prev: Token,
-    had_error: bool,
-    panic_mode: bool,
-    rules: HashMap<TokenType, ParseRule>,
+    /// The position of the token that the last instruction came from.
+    line: usize,
+    column: usize,
 }
 
 impl Compiler {
-    fn new(code: &str) -> Self {
-        use Precedence as P;
-        use TokenType::*;
-
-        let rule = |prefix, infix, precedence| ParseRule {
-            prefix,
-            infix,
-            precedence,
-        };
-        // let empty = || rule(None, None, P::None);
-
-        let rules = HashMap::from([
-            (
-                LeftParen,
-                rule(Some(Self::group), Some(Self::call), P::Call),
-            ),
-            (Minus, rule(Some(Self::unary), Some(Self::binary), P::Term)),
-            (Plus, rule(None, Some(Self::binary), P::Term)),
-            (Slash, rule(None, Some(Self::binary), P::Factor)),
-            (Star, rule(None, Some(Self::binary), P::Factor)),
-            (Bool, rule(Some(Self::bool), None, P::None)),
-            (Int, rule(Some(Self::int), None, P::None)),
-            (Float, rule(Some(Self::float), None, P::None)),
-            (Str, rule(Some(Self::string), None, P::None)),
-            (Not, rule(Some(Self::unary), None, P::None)),
-            (And, rule(None, Some(Self::and_op), P::And)),
-            (Or, rule(None, Some(Self::or_op), P::Or)),
-            (BangEqual, rule(None, Some(Self::binary), P::Equality)),
-            (EqualEqual, rule(None, Some(Self::binary), P::Equality)),
-            (Greater, rule(None, Some(Self::binary), P::Comparison)),
-            (GreaterEqual, rule(None, Some(Self::binary), P::Comparison)),
-            (Less, rule(None, Some(Self::binary), P::Comparison)),
-            (LessEqual, rule(None, Some(Self::binary), P::Comparison)),
-            (Identifier, rule(Some(Self::variable), None, P::None)),
-        ]);
-
+    fn new() -> Self {
         Compiler {
             levels: vec![Level::new(FunctionType::Script)],
-            lexer: Lexer::new(code),
-            curr: Token {
-                id: TokenType::Eof,
-                lexeme: String::new(),
-                line: 1,
-            },
-            prev: Token {
-                id: TokenType::Eof,
-                lexeme: String::new(),
-                line: 1,
-            },
-            had_error: false,
-            panic_mode: false,
-            rules,
+            line: 1,
+            column: 1,
         }
     }
 
-    fn compile(&mut self) -> Result<Function, LangError> {
-        self.next();
-        while !self.matches(TokenType::Eof) {
-            self.declaration();
+    fn compile(mut self, program: &[Stmt]) -> Function {
+        for stmt in program {
+            self.declaration(stmt);
         }
         self.emit_return();
-        self.eat(TokenType::Eof, "Expected to reach the end of the file");
-
-        if self.had_error {
-            Err(LangError::CompileError)
-        } else {
-            Ok(self.level().function.clone())
-        }
-    }
-
-    fn next(&mut self) {
-        self.prev = self.curr.clone();
-
-        loop {
-            self.curr = self.lexer.lex_token();
-            if self.curr.id != TokenType::Error {
-                break;
-            }
-            self.error_curr(&self.curr.lexeme.clone());
-        }
+        self.levels.pop().unwrap().function
     }
 
     fn level(&self) -> &Level {
@@ -192,37 +106,26 @@ impl Compiler {
         &mut self.level_mut().function.chunk
     }
 
-    fn eat(&mut self, id: TokenType, message: &str) {
-        if self.curr.id == id {
-            self.next();
-            return;
-        }
-        self.error_curr(message);
-    }
-
-    fn eat_delimit(&mut self) {
-        while self.curr.id == TokenType::Semicolon || self.curr.id == TokenType::Newline {
-            self.next();
-        }
+    /// Emits an instruction at the position of the one before it, for
+    /// instructions that cannot fail.
+    fn emit(&mut self, op: OpCode) {
+        let (line, column) = (self.line, self.column);
+        self.chunk().write(op, line, column);
     }
 
-    fn emit(&mut self, op: OpCode) {
-        let line = self.prev.line;
-        self.chunk().write(op, line);
+    /// Emits an instruction that reports its runtime errors at `token`, such
+    /// as the operator of a binary expression.
+    fn emit_at(&mut self, op: OpCode, token: &Token) {
+        self.line = token.line;
+        self.column = token.column;
+        self.emit(op);
     }
 
     fn emit_with_index(&mut self, op: OpCode) -> usize {
-        let line = self.prev.line;
-        self.chunk().write(op, line);
+        self.emit(op);
         self.chunk().code.len() - 1
     }
 
-    fn emit_two(&mut self, op1: OpCode, op2: OpCode) {
-        let line = self.prev.line;
-        self.chunk().write(op1, line);
-        self.chunk().write(op2, line);
-    }
-
     fn emit_constant(&mut self, value: Value) {
         let index = self.chunk().add_constant(value);
         self.emit(index);
@@ -242,546 +145,862 @@ impl Compiler {
         match self.chunk().code[index] {
             OpCode::Jump(ref mut x) => *x = jump,
             OpCode::JumpIfFalse(ref mut x) => *x = jump,
+            OpCode::ForIter(ref mut x) => *x = jump,
+            OpCode::Try(ref mut x) => *x = jump,
+            OpCode::Match(_, ref mut x) => *x = jump,
             op => panic!("Attempt to patch a jump with unsupported OpCode: {:?}", op),
         }
     }
 
-    fn declaration(&mut self) {
-        if self.matches(TokenType::Fun) {
-            self.fun_declaration();
-        } else if self.matches(TokenType::Var) {
-            self.var_declaration();
-        } else {
-            self.statement();
-        }
+    fn declaration(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Expression(expr) => {
+                self.expression(expr);
+                self.emit(OpCode::Pop);
+            }
+            Stmt::Print(expr) => {
+                self.expression(expr);
+                self.emit(OpCode::Print);
+            }
+            Stmt::Var { name, value, .. } => self.var_declaration(name, value),
+            Stmt::Fun(fun) => {
+                let index = self.declare_variable(&fun.name);
+                self.mark_initialized();
+                self.function(fun);
+                self.define_variable(index, &fun.name);
+            }
+            Stmt::Class(class) => self.class_declaration(class),
+            Stmt::Return { keyword, value } => match value {
+                Some(value) => {
+                    self.expression(value);
+                    self.emit_at(OpCode::Return, keyword);
+                }
+                None => {
+                    self.line = keyword.line;
+                    self.column = keyword.column;
+                    self.emit_return();
+                }
+            },
+            Stmt::Break(_) => {
+                let innermost = self.level().loops.last().unwrap();
+                let (height, tries) = (innermost.height, innermost.tries);
+                self.discard_stack(height);
+                self.leave_tries(tries);
+                let index = self.emit_with_index(OpCode::Jump(JUMP_PLACEHOLDER));
+                self.level_mut()
+                    .loops
+                    .last_mut()
+                    .unwrap()
+                    .breaks
+                    .push(index);
+            }
+            Stmt::Continue(_) => {
+                let innermost = self.level().loops.last().unwrap();
+                let (start, height, tries) = (innermost.start, innermost.height, innermost.tries);
+                self.discard_stack(height);
+                self.leave_tries(tries);
+                self.emit_jump_back(start);
+            }
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                self.expression(condition);
+                let then_index = self.emit_with_index(OpCode::JumpIfFalse(JUMP_PLACEHOLDER));
+                self.emit(OpCode::Pop);
+                self.scope_block(then_branch);
 
-        if self.panic_mode {
-            self.synchronize();
-        }
-    }
+                let else_index = self.emit_with_index(OpCode::Jump(JUMP_PLACEHOLDER));
+                self.patch_jump(then_index);
+                self.emit(OpCode::Pop);
+                if let Some(else_branch) = else_branch {
+                    self.scope_block(else_branch);
+                }
+                self.patch_jump(else_index);
+            }
+            Stmt::While {
+                condition, body, ..
+            } => {
+                let start = self.chunk_len();
+                self.expression(condition);
+                let exit_index = self.emit_with_index(OpCode::JumpIfFalse(JUMP_PLACEHOLDER));
+                self.emit(OpCode::Pop);
 
-    fn var_declaration(&mut self) {
-        let index = self.parse_variable("Expected a variable name");
+                self.begin_loop(start);
+                self.scope_block(body);
+                self.emit_jump_back(start);
 
-        if self.matches(TokenType::Equal) {
-            self.expression();
-        } else {
-            self.error_curr("Expected an expression");
+                self.patch_jump(exit_index);
+                self.emit(OpCode::Pop);
+                self.end_loop();
+            }
+            Stmt::For {
+                initializer,
+                condition,
+                increment,
+                body,
+                ..
+            } => self.for_statement(initializer.as_deref(), condition, increment, body),
+            Stmt::ForIn {
+                name,
+                collection,
+                body,
+            } => self.for_in_statement(name, collection, body),
+            Stmt::Block(stmts) => self.scope_block(stmts),
+            Stmt::Try {
+                keyword,
+                body,
+                name,
+                handler,
+            } => self.try_statement(keyword, body, name, handler),
+            Stmt::Throw { keyword, value } => {
+                self.expression(value);
+                self.emit_at(OpCode::Throw, keyword);
+            }
+            Stmt::Match(statement) => self.match_statement(statement),
+            Stmt::Broken(_) | Stmt::Error => unreachable!("Compiling a program with errors"),
         }
-        self.eat_delimit();
-        self.define_variable(index);
     }
 
-    fn fun_declaration(&mut self) {
-        let index = self.parse_variable("Expected a function name");
-        self.mark_initialized();
-        self.function(FunctionType::Function);
-        self.define_variable(index);
+    fn var_declaration(&mut self, name: &Token, value: &Expr) {
+        let index = self.declare_variable(name);
+        self.expression(value);
+        self.define_variable(index, name);
     }
 
-    fn function(&mut self, kind: FunctionType) {
-        let mut level = Level::new(kind);
-        level.function.name = self.prev.lexeme.clone();
-
-        self.levels.push(level);
-
-        self.begin_scope();
+    fn class_declaration(&mut self, class: &Class) {
+        let index = self.identifier_constant(&class.name);
+        self.declare_variable(&class.name);
+        self.emit_at(OpCode::Class(index), &class.name);
+        self.define_variable(index, &class.name);
 
-        self.eat(TokenType::LeftParen, "Expected '(' after function name");
+        if let Some(superclass) = &class.superclass {
+            self.named_variable(superclass);
 
-        if !self.check(TokenType::RightParen) {
-            loop {
-                self.level_mut().function.arity += 1;
-                let index = self.parse_variable("Expected a parameter name");
-                self.define_variable(index);
+            // The superclass stays on the stack as the local `super`, which
+            // the methods capture
+            self.begin_scope();
+            self.add_local("super");
+            self.mark_initialized();
 
-                if !self.matches(TokenType::Comma) {
-                    break;
-                }
-            }
+            self.named_variable(&class.name);
+            self.emit_at(OpCode::Inherit, superclass);
         }
-        self.eat(
-            TokenType::RightParen,
-            "Expected ')' after function parameters",
-        );
-
-        self.eat_delimit();
-        self.block();
-        self.eat_delimit();
-
-        self.emit_return();
-
-        let fun = self.levels.pop().unwrap().function;
-
-        #[cfg(debug_assertions)]
-        fun.chunk.disassemble(&fun.name);
 
-        self.emit_constant(Value::Fun(fun));
-    }
+        self.named_variable(&class.name);
+        self.level_mut().temporaries += 1;
+        for method in &class.methods {
+            let index = self.identifier_constant(&method.name);
+            self.function(method);
+            self.emit(OpCode::Method(index));
+        }
+        self.level_mut().temporaries -= 1;
+        self.emit(OpCode::Pop);
 
-    fn statement(&mut self) {
-        if self.matches(TokenType::Print) {
-            self.print_statement();
-        } else if self.matches(TokenType::Return) {
-            self.return_statement();
-        } else if self.matches(TokenType::If) {
-            self.if_statement();
-        } else if self.matches(TokenType::While) {
-            self.while_statement();
-        } else if self.matches(TokenType::For) {
-            self.for_statement();
-        } else if self.matches(TokenType::Do) {
-            self.eat_delimit();
-            self.scope_block();
-        } else {
-            self.expression_statement();
+        if class.superclass.is_some() {
+            self.end_scope();
         }
-        self.eat_delimit();
     }
 
-    fn print_statement(&mut self) {
-        self.expression();
-        self.emit(OpCode::Print);
-    }
+    fn function(&mut self, fun: &Fun) {
+        let mut level = Level::new(fun.kind);
+        level.function.name = fun.name.lexeme.clone();
+        self.levels.push(level);
+        self.begin_scope();
 
-    fn return_statement(&mut self) {
-        if self.level().function_type == FunctionType::Script {
-            self.error("Cannot return from top-level code")
+        for param in &fun.params {
+            self.add_local(&param.name.lexeme);
+            if let Some(default) = &param.default {
+                self.default_value(&param.name, default);
+            }
+
+            let function = &mut self.level_mut().function;
+            function.arity += 1;
+            function.params.push(param.name.lexeme.clone());
+            self.mark_initialized();
         }
-        if self.matches_delimit() {
-            self.emit_return();
-        } else {
-            self.expression();
-            self.eat_delimit();
-            self.emit(OpCode::Return);
+        if let Some(rest) = &fun.rest {
+            self.add_local(&rest.name.lexeme);
+            self.level_mut().function.variadic = true;
+            self.mark_initialized();
         }
-    }
-
-    fn if_statement(&mut self) {
-        self.expression();
-        self.eat_delimit();
 
-        let then_index = self.emit_with_index(OpCode::JumpIfFalse(JUMP_PLACEHOLDER));
-        self.emit(OpCode::Pop);
+        if fun.kind == FunctionType::Lambda {
+            self.value_block(&fun.body);
+            self.emit_at(OpCode::Return, &fun.end);
+        } else {
+            self.block(&fun.body.stmts);
+            self.line = fun.end.line;
+            self.column = fun.end.column;
+        }
+        self.emit_return();
 
-        self.if_block();
+        let function = self.levels.pop().unwrap().function;
 
-        let else_index = self.emit_with_index(OpCode::Jump(JUMP_PLACEHOLDER));
-        self.patch_jump(then_index);
-        self.emit(OpCode::Pop);
+        #[cfg(feature = "trace")]
+        function.chunk.disassemble(&function.name);
 
-        if self.matches(TokenType::Else) {
-            self.if_block();
-        }
-        self.patch_jump(else_index);
+        let index = self.make_constant(Value::Fun(Rc::new(function)));
+        self.emit_at(OpCode::Closure(index), &fun.end);
     }
 
-    fn while_statement(&mut self) {
-        let start = self.chunk_len();
+    /// Compiles the default value of the parameter `name` into a function,
+    /// created in the enclosing function along with the closure. A call that
+    /// leaves the parameter out calls it with the parameters before it, so
+    /// the value is evaluated afresh for each call and can use them.
+    fn default_value(&mut self, name: &Token, default: &Expr) {
+        let level = self.levels.pop().unwrap();
+        let mut thunk = Level::new(FunctionType::Lambda);
+        thunk.function.name = format!("default@{}", name.line);
+        thunk.scope_depth = 1;
+        for (slot, param) in level.locals.iter().enumerate().skip(1) {
+            if param.depth.is_some() {
+                thunk.function.arity += 1;
+                thunk.function.params.push(param.name.clone());
+            }
+            thunk.locals.push(Local {
+                slot,
+                is_captured: false,
+                ..param.clone()
+            });
+        }
+        self.levels.push(thunk);
 
-        self.expression();
-        self.eat_delimit();
+        self.expression(default);
+        self.emit(OpCode::Return);
 
-        let exit_index = self.emit_with_index(OpCode::JumpIfFalse(JUMP_PLACEHOLDER));
-        self.emit(OpCode::Pop);
+        let function = self.levels.pop().unwrap().function;
 
-        self.scope_block();
+        #[cfg(feature = "trace")]
+        function.chunk.disassemble(&function.name);
 
-        self.emit_jump_back(start);
+        let index = self.make_constant(Value::Fun(Rc::new(function)));
+        self.emit(OpCode::Closure(index));
 
-        self.patch_jump(exit_index);
-        self.emit(OpCode::Pop);
+        self.levels.push(level);
+        self.level_mut().function.defaults += 1;
     }
 
-    fn for_statement(&mut self) {
+    fn for_statement(
+        &mut self,
+        initializer: Option<&Stmt>,
+        condition: &Option<Expr>,
+        increment: &Option<Expr>,
+        body: &[Stmt],
+    ) {
         self.begin_scope();
 
-        if self.matches(TokenType::Semicolon) {
-            // No initializer
-        } else if self.matches(TokenType::Var) {
-            self.var_declaration();
-        } else {
-            self.expression_statement();
+        if let Some(initializer) = initializer {
+            self.declaration(initializer);
         }
 
         let mut start = self.chunk_len();
         let mut exit_index = None;
 
-        if !self.matches(TokenType::Semicolon) {
-            self.expression();
-            self.eat(TokenType::Semicolon, "Expected a semicolon ';'");
-
+        if let Some(condition) = condition {
+            self.expression(condition);
             exit_index = Some(self.emit_with_index(OpCode::JumpIfFalse(JUMP_PLACEHOLDER)));
             self.emit(OpCode::Pop);
         }
 
-        if !self.matches_delimit() {
+        if let Some(increment) = increment {
             let body_index = self.emit_with_index(OpCode::Jump(JUMP_PLACEHOLDER));
             let increment_start = self.chunk_len();
-            self.expression();
+            self.expression(increment);
             self.emit(OpCode::Pop);
-            self.eat_delimit();
-
             self.emit_jump_back(start);
 
             start = increment_start;
             self.patch_jump(body_index);
         }
 
-        self.scope_block();
-
+        self.begin_loop(start);
+        self.scope_block(body);
         self.emit_jump_back(start);
 
         if let Some(index) = exit_index {
             self.patch_jump(index);
             self.emit(OpCode::Pop);
         }
+        self.end_loop();
 
         self.end_scope();
     }
 
-    fn expression_statement(&mut self) {
-        self.expression();
-        self.emit(OpCode::Pop);
-    }
+    fn for_in_statement(&mut self, name: &Token, collection: &Expr, body: &[Stmt]) {
+        self.begin_scope();
 
-    fn expression(&mut self) {
-        self.parse_precedence(Precedence::Assignment);
-    }
+        self.expression(collection);
 
-    fn block(&mut self) {
-        while !self.check(TokenType::End) && !self.check(TokenType::Eof) {
-            self.declaration();
-        }
-        self.eat(TokenType::End, "Expected 'end' after block");
-    }
+        // The iterator lives in a hidden local for the whole loop
+        self.emit_at(OpCode::Iter, &collection.last);
+        self.add_local("for iterator");
+        self.mark_initialized();
 
-    fn scope_block(&mut self) {
-        self.begin_scope();
-        self.block();
-        self.end_scope();
-    }
+        let start = self.chunk_len();
+        let exit_index = self.emit_with_index(OpCode::ForIter(JUMP_PLACEHOLDER));
+        self.begin_loop(start);
 
-    fn if_block(&mut self) {
-        self.eat_delimit();
+        // Each iteration gets a fresh loop variable, so closures that
+        // capture it see the value from their own iteration
         self.begin_scope();
+        self.add_local(&name.lexeme);
+        self.mark_initialized();
+        self.block(body);
+        self.end_scope();
 
-        while !self.check(TokenType::Else)
-            && !self.check(TokenType::End)
-            && !self.check(TokenType::Eof)
-        {
-            self.declaration();
-        }
-        if self.curr.id != TokenType::Else {
-            self.eat(TokenType::End, "Expected 'end' after if block'");
-        }
+        self.emit_jump_back(start);
+        self.patch_jump(exit_index);
+        self.end_loop();
 
         self.end_scope();
     }
 
-    fn bool(&mut self, _can_assign: bool) {
-        let value = self.prev.lexeme.parse::<bool>().unwrap();
-        self.emit_constant(Value::Bool(value));
+    fn begin_loop(&mut self, start: usize) {
+        let height = self.stack_height();
+        let tries = self.level().tries;
+        self.level_mut().loops.push(Loop {
+            start,
+            height,
+            tries,
+            breaks: Vec::new(),
+        });
     }
 
-    fn int(&mut self, _can_assign: bool) {
-        let value = match self.prev.lexeme.parse::<isize>() {
-            Ok(v) => v,
-            Err(_) => {
-                return self.error(&format!(
-                    "Integer is out of the range {}..{}",
-                    isize::MIN + 1,
-                    isize::MAX
-                ))
-            }
-        };
-
-        self.emit_constant(Value::Int(value));
+    fn end_loop(&mut self) {
+        let innermost = self.level_mut().loops.pop().unwrap();
+        for index in innermost.breaks {
+            self.patch_jump(index);
+        }
     }
 
-    fn float(&mut self, _can_assign: bool) {
-        let value = self.prev.lexeme.parse::<f64>().unwrap();
-        self.emit_constant(Value::Float(value));
+    /// Removes the handlers of the `try` blocks entered after `tries`, for
+    /// jumps that leave those blocks early.
+    fn leave_tries(&mut self, tries: usize) {
+        for _ in tries..self.level().tries {
+            self.emit(OpCode::EndTry);
+        }
     }
 
-    fn string(&mut self, _can_assign: bool) {
-        let lexeme = self.prev.lexeme.clone();
-        self.emit_constant(Value::Str(lexeme[1..lexeme.len() - 1].to_string()));
-    }
+    /// Compiles a `try` block and its `catch` block. An error raised while
+    /// the `try` block runs cuts the stack back to where the block began
+    /// and resumes at the `catch` block, with the error in its variable.
+    fn try_statement(&mut self, keyword: &Token, body: &[Stmt], name: &Token, handler: &[Stmt]) {
+        self.line = keyword.line;
+        self.column = keyword.column;
+        let try_index = self.emit_with_index(OpCode::Try(JUMP_PLACEHOLDER));
 
-    fn variable(&mut self, can_assign: bool) {
-        self.named_variable(self.prev.clone(), can_assign);
+        self.level_mut().tries += 1;
+        self.scope_block(body);
+        self.level_mut().tries -= 1;
+
+        self.emit(OpCode::EndTry);
+        let end_index = self.emit_with_index(OpCode::Jump(JUMP_PLACEHOLDER));
+        self.patch_jump(try_index);
+
+        self.begin_scope();
+        self.add_local(&name.lexeme);
+        self.mark_initialized();
+        self.block(handler);
+        self.end_scope();
+
+        self.patch_jump(end_index);
     }
 
-    fn named_variable(&mut self, name: Token, can_assign: bool) {
-        let get_op;
-        let set_op;
+    /// Compiles a `match` whose cases are blocks of statements.
+    fn match_statement(&mut self, statement: &Match<Vec<Stmt>>) {
+        let slot = self.match_subject(&statement.subject);
 
-        if let Some(index) = self.resolve_local(&name) {
-            get_op = OpCode::GetLocal(index);
-            set_op = OpCode::SetLocal(index);
-        } else {
-            let index = self.identifier_constant(name);
-            get_op = OpCode::GetGlobal(index);
-            set_op = OpCode::SetGlobal(index);
+        let mut ends = Vec::new();
+        for case in &statement.cases {
+            let next = self.case_pattern(slot, case.pattern.as_ref());
+            self.block(&case.body);
+            self.end_scope();
+            self.end_case(next, &mut ends);
         }
 
-        if can_assign && self.matches(TokenType::Equal) {
-            self.expression();
-            self.emit(set_op);
-        } else {
-            self.emit(get_op);
-        }
+        self.end_match(&statement.keyword, slot, ends);
+        self.end_scope();
     }
 
-    fn define_variable(&mut self, index: usize) {
-        if self.level().scope_depth > 0 {
+    /// Compiles the value a `match` is on into a hidden local, in a scope
+    /// that the caller closes after the last case. Returns the slot of the
+    /// local.
+    fn match_subject(&mut self, subject: &Expr) -> usize {
+        self.begin_scope();
+        self.expression(subject);
+        self.add_local("match value");
+        self.mark_initialized();
+        self.level().locals.last().unwrap().slot
+    }
+
+    /// Compiles the test of the hidden local at `slot` against the pattern
+    /// of a `case`. Opens a scope with the value inside the variant bound in
+    /// it. Returns the jump to the next case.
+    fn case_pattern(&mut self, slot: usize, pattern: Option<&Pattern>) -> Option<usize> {
+        self.begin_scope();
+        let pattern = pattern?;
+
+        self.emit_at(OpCode::GetLocal(slot), &pattern.token);
+        let next = self.emit_with_index(OpCode::Match(pattern.variant, JUMP_PLACEHOLDER));
+        if let Some(name) = &pattern.binding {
+            self.add_local(&name.lexeme);
             self.mark_initialized();
-            return;
         }
-        self.emit(OpCode::DefineGlobal(index));
+        Some(next)
     }
 
-    fn group(&mut self, _can_assign: bool) {
-        self.expression();
-        self.eat(TokenType::RightParen, "Expected closing parenthesis ')'");
+    /// Jumps from the end of a case to the end of the match, and makes the
+    /// test of the case jump to the next one when it fails.
+    fn end_case(&mut self, next: Option<usize>, ends: &mut Vec<usize>) {
+        ends.push(self.emit_with_index(OpCode::Jump(JUMP_PLACEHOLDER)));
+        if let Some(next) = next {
+            self.patch_jump(next);
+        }
     }
 
-    fn unary(&mut self, _can_assign: bool) {
-        let operator_id = self.prev.id;
+    /// Makes a value that no case matched an error. The checker has made
+    /// sure that the cases cover every variant when the type of the value
+    /// is known, so this only happens to values only known at runtime.
+    fn end_match(&mut self, keyword: &Token, slot: usize, ends: Vec<usize>) {
+        self.emit(OpCode::GetLocal(slot));
+        self.emit_at(OpCode::NoMatch, keyword);
+        for index in ends {
+            self.patch_jump(index);
+        }
+    }
 
-        self.parse_precedence(Precedence::Unary);
+    /// Pops everything above `height` off the stack without forgetting the
+    /// locals, for jumps that leave their scopes early. That is the locals of
+    /// those scopes and the operands of any expression the jump leaves, such
+    /// as `1 + do break end`. A closure further down the scope may still
+    /// capture any of the locals, so they are all closed rather than popped.
+    fn discard_stack(&mut self, height: usize) {
+        for _ in height..self.stack_height() {
+            self.emit(OpCode::CloseUpvalue);
+        }
+    }
 
-        match operator_id {
-            TokenType::Minus => self.emit(OpCode::Negate),
-            TokenType::Not => self.emit(OpCode::Not),
-            _ => (),
+    fn block(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            self.declaration(stmt);
         }
     }
 
-    fn binary(&mut self, _can_assign: bool) {
-        let operator_id = self.prev.id;
-        let rule = self.get_rule(operator_id).precedence.next();
+    fn scope_block(&mut self, stmts: &[Stmt]) {
+        self.begin_scope();
+        self.block(stmts);
+        self.end_scope();
+    }
 
-        self.parse_precedence(rule);
+    /// Compiles the statements of a block and leaves its value on the stack:
+    /// the value of its last statement if that is an expression, and void
+    /// otherwise.
+    fn value_block(&mut self, block: &Block) {
+        self.begin_scope();
 
-        use OpCode::*;
-        use TokenType::*;
-        match operator_id {
-            Plus => self.emit(Add),
-            Minus => self.emit(Subtract),
-            Star => self.emit(Multiply),
-            Slash => self.emit(Divide),
-            BangEqual => self.emit_two(OpCode::Equal, OpCode::Not),
-            EqualEqual => self.emit(OpCode::Equal),
-            TokenType::Greater => self.emit(OpCode::Greater),
-            GreaterEqual => self.emit_two(OpCode::Less, OpCode::Not),
-            TokenType::Less => self.emit(OpCode::Less),
-            LessEqual => self.emit_two(OpCode::Greater, OpCode::Not),
-            _ => (),
+        for stmt in &block.stmts {
+            self.declaration(stmt);
+        }
+        match &block.value {
+            Some(value) => self.expression(value),
+            None => self.emit_constant(Value::Void),
         }
-    }
 
-    fn call(&mut self, _can_assign: bool) {
-        let arg_len = self.argument_list();
-        self.emit(OpCode::Call(arg_len));
+        self.end_value_scope();
     }
 
-    fn argument_list(&mut self) -> usize {
-        let mut arg_len = 0;
-        if !self.check(TokenType::RightParen) {
-            loop {
-                self.expression();
-                arg_len += 1;
+    /// Like `end_scope`, for a scope whose value sits on the stack above its
+    /// locals.
+    fn end_value_scope(&mut self) {
+        self.level_mut().scope_depth -= 1;
 
-                if !self.matches(TokenType::Comma) {
-                    break;
-                }
+        let scope_depth = self.level().scope_depth;
+        let mut len = 0;
+        while let Some(local) = self.level().locals.last() {
+            // A local without a depth is the one whose initializer this
+            // scope is part of
+            if local.depth.is_none_or(|depth| depth <= scope_depth) {
+                break;
             }
+            self.level_mut().locals.pop();
+            len += 1;
+        }
+        if len > 0 {
+            self.emit(OpCode::EndBlock(len));
         }
-        self.eat(TokenType::RightParen, "Expected ')' after arguments");
-        arg_len
     }
 
-    fn and_op(&mut self, _can_assign: bool) {
-        let index = self.emit_with_index(OpCode::JumpIfFalse(JUMP_PLACEHOLDER));
+    /// Compiles an expression, leaving its value on the stack.
+    fn expression(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Literal(value) => {
+                self.line = expr.first.line;
+                self.column = expr.first.column;
+                self.emit_constant(value.clone());
+            }
+            ExprKind::Interpolation(parts) => {
+                // The parts stay on the stack until they are joined
+                for part in parts {
+                    self.operand(part);
+                }
+                self.level_mut().temporaries -= parts.len();
+                self.emit(OpCode::Interpolate(parts.len()));
+            }
+            ExprKind::Variable(name) | ExprKind::SelfKw(name) => self.named_variable(name),
+            ExprKind::Super { keyword, method } => {
+                let index = self.identifier_constant(method);
+                self.named_variable(&synthetic_token("self", keyword));
+                self.named_variable(&synthetic_token("super", keyword));
+                self.emit_at(OpCode::GetSuper(index), method);
+            }
+            ExprKind::Assign {
+                name,
+                operator,
+                op,
+                value,
+            } => {
+                let (get_op, set_op) = self.resolve_variable(name);
+                if let Some(op) = op {
+                    self.emit_at(get_op, operator);
+                    self.compound_value(operator, *op, value);
+                } else {
+                    self.expression(value);
+                }
+                self.emit_at(set_op, &value.last);
+            }
+            ExprKind::Get { object, name } => {
+                self.expression(object);
+                let index = self.identifier_constant(name);
+                self.emit_at(OpCode::GetProperty(index), name);
+            }
+            ExprKind::Set {
+                object,
+                name,
+                operator,
+                op,
+                value,
+            } => {
+                let index = self.identifier_constant(name);
+                // The receiver stays on the stack below the value
+                self.operand(object);
+                if let Some(op) = op {
+                    self.emit(OpCode::Duplicate(1));
+                    self.emit_at(OpCode::GetProperty(index), name);
+                    self.compound_value(operator, *op, value);
+                } else {
+                    self.expression(value);
+                }
+                self.level_mut().temporaries -= 1;
+                self.emit_at(OpCode::SetProperty(index), name);
+            }
+            ExprKind::Index {
+                collection,
+                bracket,
+                index,
+            } => {
+                self.operand(collection);
+                self.expression(index);
+                self.level_mut().temporaries -= 1;
+                self.emit_at(OpCode::GetIndex, bracket);
+            }
+            ExprKind::SetIndex {
+                collection,
+                bracket,
+                index,
+                operator,
+                op,
+                value,
+            } => {
+                // The collection and the index stay on the stack below the value
+                self.operand(collection);
+                self.operand(index);
+                if let Some(op) = op {
+                    self.emit(OpCode::Duplicate(2));
+                    self.emit_at(OpCode::GetIndex, bracket);
+                    self.compound_value(operator, *op, value);
+                } else {
+                    self.expression(value);
+                }
+                self.level_mut().temporaries -= 2;
+                self.emit_at(OpCode::SetIndex, bracket);
+            }
+            ExprKind::Call {
+                callee,
+                paren,
+                args,
+                names,
+            } => {
+                self.operand(callee);
+                for arg in args {
+                    self.operand(arg);
+                }
+                self.level_mut().temporaries -= 1 + args.len();
+
+                if names.is_empty() {
+                    self.emit_at(OpCode::Call(args.len()), paren);
+                } else {
+                    let names = names.iter().cloned().map(Value::Str).collect();
+                    let index = self.make_constant(Value::List(Rc::new(RefCell::new(names))));
+                    self.emit_at(OpCode::CallNamed(args.len(), index), paren);
+                }
+            }
+            ExprKind::Unary {
+                operator,
+                op,
+                operand,
+            } => {
+                self.expression(operand);
+                self.emit_at(*op, operator);
+            }
+            ExprKind::Binary {
+                operator,
+                op,
+                left,
+                right,
+            } => {
+                self.operand(left);
+                self.expression(right);
+                self.level_mut().temporaries -= 1;
+
+                self.emit_at(*op, operator);
+                if matches!(
+                    operator.id,
+                    TokenType::BangEqual | TokenType::GreaterEqual | TokenType::LessEqual
+                ) {
+                    self.emit_at(OpCode::Not, operator);
+                }
+            }
+            ExprKind::Logical {
+                operator,
+                left,
+                right,
+            } => {
+                self.expression(left);
+                self.line = operator.line;
+                self.column = operator.column;
+                if operator.id == TokenType::And {
+                    let index = self.emit_with_index(OpCode::JumpIfFalse(JUMP_PLACEHOLDER));
+                    self.emit(OpCode::Pop);
+                    self.expression(right);
+                    self.patch_jump(index);
+                } else {
+                    let else_index = self.emit_with_index(OpCode::JumpIfFalse(JUMP_PLACEHOLDER));
+                    let end_index = self.emit_with_index(OpCode::Jump(JUMP_PLACEHOLDER));
+                    self.patch_jump(else_index);
+                    self.emit(OpCode::Pop);
+                    self.expression(right);
+                    self.patch_jump(end_index);
+                }
+            }
+            ExprKind::Contains {
+                operator,
+                item,
+                collection,
+            } => {
+                self.operand(item);
+                self.expression(collection);
+                self.level_mut().temporaries -= 1;
+                self.emit_at(OpCode::In, operator);
+            }
+            ExprKind::List(elements) => {
+                // The elements stay on the stack until the list is built
+                for element in elements {
+                    self.operand(element);
+                }
+                self.level_mut().temporaries -= elements.len();
+                self.emit_at(OpCode::BuildList(elements.len()), &expr.last);
+            }
+            ExprKind::Map(entries) => {
+                // The entries stay on the stack until the map is built
+                for (key, value) in entries {
+                    self.operand(key);
+                    self.operand(value);
+                }
+                self.level_mut().temporaries -= 2 * entries.len();
+                self.emit_at(OpCode::BuildMap(entries.len()), &expr.last);
+            }
+            ExprKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expression(condition);
+                let then_index = self.emit_with_index(OpCode::JumpIfFalse(JUMP_PLACEHOLDER));
+                self.emit(OpCode::Pop);
+                self.value_block(then_branch);
 
-        self.emit(OpCode::Pop);
-        self.parse_precedence(Precedence::And);
+                let else_index = self.emit_with_index(OpCode::Jump(JUMP_PLACEHOLDER));
+                self.patch_jump(then_index);
+                self.emit(OpCode::Pop);
+                match else_branch {
+                    Some(else_branch) => self.value_block(else_branch),
+                    None => self.emit_constant(Value::Void),
+                }
+                self.patch_jump(else_index);
+            }
+            ExprKind::Do(block) => self.value_block(block),
+            ExprKind::Match(expression) => self.match_expression(expression),
+            ExprKind::Wrap { variant, value } => {
+                self.expression(value);
+                self.emit_at(OpCode::Wrap(*variant), &expr.last);
+            }
+            ExprKind::None => {
+                self.line = expr.first.line;
+                self.column = expr.first.column;
+                self.emit_constant(Value::Variant(Variant::None, Rc::new(Value::Void)));
+            }
+            ExprKind::Propagate { operator, value } => {
+                self.expression(value);
+                self.emit_at(OpCode::Propagate, operator);
+            }
+            ExprKind::Lambda(fun) => self.function(fun),
+            ExprKind::Error => unreachable!("Compiling a program with errors"),
+        }
+    }
 
-        self.patch_jump(index);
+    /// Compiles an expression whose value stays on the stack while the
+    /// expressions after it are compiled, until the caller uses it up.
+    fn operand(&mut self, expr: &Expr) {
+        self.expression(expr);
+        self.level_mut().temporaries += 1;
     }
 
-    fn or_op(&mut self, _can_assign: bool) {
-        let else_index = self.emit_with_index(OpCode::JumpIfFalse(JUMP_PLACEHOLDER));
-        let end_index = self.emit_with_index(OpCode::Jump(JUMP_PLACEHOLDER));
+    /// Compiles a `match` whose cases are blocks with a value, like
+    /// `value_block`, and leaves the value of the case that ran.
+    fn match_expression(&mut self, expression: &Match<Block>) {
+        let slot = self.match_subject(&expression.subject);
 
-        self.patch_jump(else_index);
-        self.emit(OpCode::Pop);
+        let mut ends = Vec::new();
+        for case in &expression.cases {
+            let next = self.case_pattern(slot, case.pattern.as_ref());
+            self.value_block(&case.body);
+            self.end_value_scope();
+            self.end_case(next, &mut ends);
+        }
 
-        self.parse_precedence(Precedence::Or);
-        self.patch_jump(end_index);
+        self.end_match(&expression.keyword, slot, ends);
+        self.end_value_scope();
     }
 
-    fn _literal(&mut self) {
-        // match self.prev.id {
-        //     _ => (),
-        // }
+    /// Compiles the right-hand side of a compound assignment, with the
+    /// current value of the target already on the stack, and applies the
+    /// operator to both.
+    fn compound_value(&mut self, operator: &Token, op: OpCode, value: &Expr) {
+        self.level_mut().temporaries += 1;
+        self.expression(value);
+        self.level_mut().temporaries -= 1;
+        self.emit_at(op, operator);
     }
 
-    fn parse_precedence(&mut self, precedence: Precedence) {
-        self.next();
-        let prefix_rule = self.get_rule(self.prev.id).prefix;
-
-        let prefix_rule = match prefix_rule {
-            Some(rule) => rule,
-            None => return self.error("Expected expression"),
-        };
-
-        let can_assign = precedence <= Precedence::Assignment;
-        prefix_rule(self, can_assign);
+    /// The instructions that read and write the variable `name`.
+    fn resolve_variable(&mut self, name: &Token) -> (OpCode, OpCode) {
+        let level = self.levels.len() - 1;
 
-        while precedence <= self.get_rule(self.curr.id).precedence {
-            self.next();
-            let infix_rule = self.get_rule(self.prev.id).infix.unwrap();
-            infix_rule(self, can_assign);
-
-            if can_assign && self.matches(TokenType::Equal) {
-                self.error("Invalid assignment target");
-            }
+        if let Some(index) = self.resolve_local(level, &name.lexeme) {
+            let slot = self.levels[level].locals[index].slot;
+            (OpCode::GetLocal(slot), OpCode::SetLocal(slot))
+        } else if let Some(index) = self.resolve_upvalue(level, &name.lexeme) {
+            (OpCode::GetUpvalue(index), OpCode::SetUpvalue(index))
+        } else {
+            let index = self.identifier_constant(name);
+            (OpCode::GetGlobal(index), OpCode::SetGlobal(index))
         }
     }
 
-    fn get_rule(&self, id: TokenType) -> &ParseRule {
-        self.rules.get(&id).unwrap_or(&ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: Precedence::None,
-        })
-        // .unwrap_or_else(|| panic!("Undefined rule {:?}", id))
+    fn named_variable(&mut self, name: &Token) {
+        let (get_op, _) = self.resolve_variable(name);
+        self.emit_at(get_op, name);
     }
 
-    fn parse_variable(&mut self, message: &str) -> usize {
-        self.eat(TokenType::Identifier, message);
-        self.declare_variable();
+    fn declare_variable(&mut self, name: &Token) -> usize {
         if self.level().scope_depth > 0 {
+            self.add_local(&name.lexeme);
             return 0;
         }
-        self.identifier_constant(self.prev.clone())
+        self.identifier_constant(name)
     }
 
-    fn mark_initialized(&mut self) {
+    fn define_variable(&mut self, index: usize, name: &Token) {
         if self.level().scope_depth > 0 {
-            self.level_mut().locals.last_mut().unwrap().depth = Some(self.level().scope_depth);
-        }
-    }
-
-    fn identifier_constant(&mut self, token: Token) -> usize {
-        let name = Value::Str(token.lexeme);
-        self.chunk().add_constant(name);
-        self.chunk().constants.len() - 1
-    }
-
-    fn declare_variable(&mut self) {
-        if self.level().scope_depth == 0 {
+            self.mark_initialized();
             return;
         }
+        self.emit_at(OpCode::DefineGlobal(index), name);
+    }
 
-        let name = self.prev.clone();
-        if self.search_locals(&name) {
-            self.error("Cannot redeclare variable in this scope");
+    fn mark_initialized(&mut self) {
+        if self.level().scope_depth > 0 {
+            self.level_mut().locals.last_mut().unwrap().depth = Some(self.level().scope_depth);
         }
-        self.add_local(name);
     }
 
-    fn search_locals(&self, name: &Token) -> bool {
-        for local in self.level().locals.iter().rev() {
-            if local.depth.is_some() && local.depth.unwrap() < self.level().scope_depth {
-                return false;
-            }
-            if local.name.lexeme == name.lexeme {
-                return true;
-            }
-        }
-        false
+    fn identifier_constant(&mut self, token: &Token) -> usize {
+        self.make_constant(Value::Str(token.lexeme.clone()))
     }
 
-    fn resolve_local(&mut self, name: &Token) -> Option<usize> {
-        for (index, local) in self.level().locals.iter().enumerate().rev() {
-            if name.lexeme == local.name.lexeme {
-                if local.depth.is_none() {
-                    self.error("Cannot read local variable in its own initializer");
-                }
-                return Some(index);
-            }
-        }
-        None
+    fn make_constant(&mut self, value: Value) -> usize {
+        self.chunk().add_constant(value);
+        self.chunk().constants.len() - 1
     }
 
-    fn add_local(&mut self, name: Token) {
-        self.level_mut().locals.push(Local { name, depth: None });
+    fn resolve_local(&self, level: usize, name: &str) -> Option<usize> {
+        self.levels[level]
+            .locals
+            .iter()
+            .rposition(|local| local.name == name)
     }
 
-    fn matches(&mut self, id: TokenType) -> bool {
-        if self.check(id) {
-            self.next();
-            true
-        } else {
-            false
+    fn resolve_upvalue(&mut self, level: usize, name: &str) -> Option<usize> {
+        if level == 0 {
+            return None;
         }
-    }
 
-    fn matches_delimit(&mut self) -> bool {
-        if self.check(TokenType::Semicolon) || self.check(TokenType::Newline) {
-            self.next();
-            true
-        } else {
-            false
+        if let Some(index) = self.resolve_local(level - 1, name) {
+            let local = &mut self.levels[level - 1].locals[index];
+            local.is_captured = true;
+            let slot = local.slot;
+            return Some(self.add_upvalue(level, slot, true));
         }
-    }
 
-    fn check(&self, id: TokenType) -> bool {
-        self.curr.id == id
-    }
+        if let Some(index) = self.resolve_upvalue(level - 1, name) {
+            return Some(self.add_upvalue(level, index, false));
+        }
 
-    fn error_curr(&mut self, msg: &str) {
-        self.error_at(self.curr.clone(), msg);
+        None
     }
 
-    fn error(&mut self, msg: &str) {
-        self.error_at(self.prev.clone(), msg);
-    }
+    fn add_upvalue(&mut self, level: usize, index: usize, is_local: bool) -> usize {
+        let upvalue = UpvalueIndex { index, is_local };
+        let upvalues = &mut self.levels[level].function.upvalues;
 
-    fn error_at(&mut self, token: Token, msg: &str) {
-        if self.panic_mode {
-            return;
-        }
-        self.had_error = true;
-        self.panic_mode = true;
-        eprint!("[line {}] Error", token.line);
-        if token.id == TokenType::Eof {
-            eprint!(" at end of file");
-        } else {
-            eprint!(" at `{}`", token.lexeme);
+        if let Some(existing) = upvalues.iter().position(|u| *u == upvalue) {
+            return existing;
         }
-        eprintln!(": {}", msg);
+        upvalues.push(upvalue);
+        upvalues.len() - 1
     }
 
-    fn synchronize(&mut self) {
-        use TokenType::*;
-        self.panic_mode = false;
-
-        while self.curr.id != Eof {
-            if self.prev.id == Semicolon || self.prev.id == Newline {
-                return;
-            }
+    fn add_local(&mut self, name: &str) {
+        let slot = self.stack_height();
+        self.level_mut().locals.push(Local {
+            name: String::from(name),
+            depth: None,
+            slot,
+            is_captured: false,
+        });
+    }
 
-            match self.curr.id {
-                Class | Fun | Var | For | If | While | Print | Return => return,
-                _ => (),
-            }
-            self.next();
-        }
+    /// How many values the current function has on the stack: its locals
+    /// and the operands of the expressions being compiled.
+    fn stack_height(&self) -> usize {
+        let level = self.level();
+        // Locals whose initializer is still being compiled are not on the
+        // stack yet
+        let locals = level.locals.iter().filter(|l| l.depth.is_some()).count();
+        locals + level.temporaries
     }
 
     fn begin_scope(&mut self) {
@@ -791,29 +1010,56 @@ impl Compiler {
     fn end_scope(&mut self) {
         self.level_mut().scope_depth -= 1;
 
-        for i in (0..self.level_mut().locals.len()).rev() {
-            if self.level_mut().locals[i].depth.unwrap() > self.level_mut().scope_depth {
+        while let Some(local) = self.level().locals.last() {
+            if local
+                .depth
+                .is_none_or(|depth| depth <= self.level().scope_depth)
+            {
+                break;
+            }
+            if local.is_captured {
+                self.emit(OpCode::CloseUpvalue);
+            } else {
                 self.emit(OpCode::Pop);
-                self.level_mut().locals.pop();
             }
+            self.level_mut().locals.pop();
         }
     }
 
     fn emit_return(&mut self) {
-        self.emit_constant(Value::Void);
+        if self.level().function_type == FunctionType::Initializer {
+            self.emit(OpCode::GetLocal(0));
+        } else {
+            self.emit_constant(Value::Void);
+        }
         self.emit(OpCode::Return);
     }
 }
 
-pub fn compile(code: &str) -> Result<Function, LangError> {
-    let mut compiler = Compiler::new(code);
-    let passed = compiler.compile();
-    if passed.is_ok() {
-        #[cfg(debug_assertions)]
-        compiler.chunk().disassemble("<script>");
+/// A token for a name the compiler refers to itself, such as `self` in
+/// `super.method`.
+fn synthetic_token(lexeme: &str, at: &Token) -> Token {
+    Token {
+        id: TokenType::Identifier,
+        lexeme: String::from(lexeme),
+        ..at.clone()
+    }
+}
 
-        Ok(compiler.level().function.clone())
-    } else {
-        Err(LangError::CompileError)
+/// Compiles a script in three passes: parsing it, checking the parsed
+/// program, and emitting its bytecode when there are no errors.
+pub fn compile(code: &str) -> Result<Function, LangError> {
+    let (program, mut diagnostics) = parser::parse(code);
+    diagnostics.extend(checker::check(&program));
+    if !diagnostics.is_empty() {
+        diagnostics.sort_by_key(|diagnostic| (diagnostic.line, diagnostic.column));
+        return Err(LangError::CompileError(diagnostics));
     }
+
+    let function = Compiler::new().compile(&program);
+
+    #[cfg(feature = "trace")]
+    function.chunk.disassemble("<script>");
+
+    Ok(function)
 }
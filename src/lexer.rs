@@ -1,12 +1,34 @@
-use crate::token::{Token, TokenType};
+use crate::token::{Span, Token, TokenType};
 use std::collections::HashMap;
 
+/// An embedded expression that is being lexed.
+#[derive(Clone)]
+struct OpenInterpolation {
+    /// The quote of the string the expression is in.
+    quote: char,
+    /// How many braces inside the expression are still open.
+    depth: usize,
+    /// The `{` that starts the expression, reported if it is never closed.
+    brace: Token,
+}
+
 pub struct Lexer {
     chars: Vec<char>,
+    /// The byte offset in the source of each char, and of the end.
+    offsets: Vec<usize>,
     start: usize,
     curr: usize,
     line: usize,
+    /// The char index where the current line begins.
+    line_start: usize,
+    /// The column of the token being lexed.
+    start_column: usize,
+    /// The strings whose `{expr}` is being lexed, innermost last.
+    interpolations: Vec<OpenInterpolation>,
     keywords: HashMap<&'static str, TokenType>,
+    /// Whether the last token can end an assignment target. Only there is
+    /// `--` a decrement, so that `--x` still negates twice.
+    after_target: bool,
 }
 
 impl Lexer {
@@ -19,13 +41,17 @@ impl Lexer {
             ("and", And),
             ("not", Not),
             ("if", If),
+            ("then", Then),
             ("else", Else),
             ("while", While),
             ("for", For),
             ("var", Var),
             ("let", Let),
+            ("in", In),
             ("fun", Fun),
             ("return", Return),
+            ("break", Break),
+            ("continue", Continue),
             ("class", Class),
             ("super", Super),
             ("self", SelfKw),
@@ -34,25 +60,51 @@ impl Lexer {
             ("print", Print),
             ("do", Do),
             ("end", End),
+            ("try", Try),
+            ("catch", Catch),
+            ("throw", Throw),
+            ("match", Match),
+            ("case", Case),
+            ("Some", SomeKw),
+            ("None", NoneKw),
+            ("Ok", OkKw),
+            ("Err", ErrKw),
         ]);
 
         let mut chars: Vec<char> = code.chars().collect();
         chars.push('\0');
+        let mut offsets: Vec<usize> = code.char_indices().map(|(offset, _)| offset).collect();
+        offsets.push(code.len());
 
         Lexer {
             chars,
+            offsets,
             start: 0,
             curr: 0,
             line: 1,
+            line_start: 0,
+            start_column: 1,
+            interpolations: Vec::new(),
             keywords,
+            after_target: false,
         }
     }
     pub fn lex_token(&mut self) -> Token {
+        let token = self.scan_token();
+        self.after_target = matches!(token.id, TokenType::Identifier | TokenType::RightBracket);
+        token
+    }
+
+    fn scan_token(&mut self) -> Token {
         use TokenType::*;
 
         self.skip_whitespace();
         self.start = self.curr;
+        self.start_column = self.curr - self.line_start + 1;
         if self.at_end() {
+            if let Some(interpolation) = self.interpolations.pop() {
+                return self.unterminated_interpolation(interpolation);
+            }
             return self.make_token(Eof);
         }
 
@@ -62,21 +114,63 @@ impl Lexer {
             '\n' => return self.make_newline(),
             '(' => LeftParen,
             ')' => RightParen,
-            '{' => LeftBrace,
-            '}' => RightBrace,
+            '{' => {
+                if let Some(interpolation) = self.interpolations.last_mut() {
+                    interpolation.depth += 1;
+                }
+                LeftBrace
+            }
+            '}' => match self.interpolations.last_mut() {
+                Some(OpenInterpolation { depth: 0, .. }) => {
+                    let quote = self.interpolations.pop().unwrap().quote;
+                    return self.make_string(quote);
+                }
+                Some(interpolation) => {
+                    interpolation.depth -= 1;
+                    RightBrace
+                }
+                None => RightBrace,
+            },
+            '[' => LeftBracket,
+            ']' => RightBracket,
             ';' => Semicolon,
             ',' => Comma,
+            ':' => Colon,
+            '.' if self.matches('.') => {
+                if self.matches('.') {
+                    Ellipsis
+                } else {
+                    DotDot
+                }
+            }
             '.' => Dot,
-            '+' => Plus,
-            '-' => Minus,
-            '*' => Star,
-            '/' => Slash,
+            '+' if self.matches('+') => PlusPlus,
+            '+' => self.if_eq(PlusEqual, Plus),
+            '-' if self.matches('>') => Arrow,
+            '-' if self.after_target && self.matches('-') => MinusMinus,
+            '-' => self.if_eq(MinusEqual, Minus),
+            '*' if self.matches('*') => StarStar,
+            '*' => self.if_eq(StarEqual, Star),
+            '%' => self.if_eq(PercentEqual, Percent),
+            '~' if self.matches('/') => TildeSlash,
+            '~' => Tilde,
+            '&' => Ampersand,
+            '|' => Pipe,
+            '^' => Caret,
+            '?' => Question,
+            '/' => self.if_eq(SlashEqual, Slash),
             '!' => self.if_eq(BangEqual, Bang),
             '=' => self.if_eq(EqualEqual, Equal),
+            '<' if self.matches('<') => LessLess,
             '<' => self.if_eq(LessEqual, Less),
+            '>' if self.matches('>') => GreaterGreater,
             '>' => self.if_eq(GreaterEqual, Greater),
             '"' | '\'' => return self.make_string(curr),
-            curr if curr.is_digit(10) => return self.make_number(),
+            'r' if self.peek() == '"' || self.peek() == '\'' => {
+                let quote = self.next();
+                return self.make_raw_string(quote);
+            }
+            curr if curr.is_ascii_digit() => return self.make_number(),
             curr if curr.is_alphabetic() || curr == '_' => return self.make_identifier(),
             _ => return self.make_error(format!("Unexpected character: {}", curr)),
         };
@@ -84,11 +178,25 @@ impl Lexer {
         self.make_token(token)
     }
 
+    /// Lexes the token after the current one without consuming it.
+    pub fn peek_token(&mut self) -> Token {
+        let (start, curr, line) = (self.start, self.curr, self.line);
+        let (line_start, start_column) = (self.line_start, self.start_column);
+        let (interpolations, after_target) = (self.interpolations.clone(), self.after_target);
+        let token = self.lex_token();
+        (self.start, self.curr, self.line) = (start, curr, line);
+        (self.line_start, self.start_column) = (line_start, start_column);
+        (self.interpolations, self.after_target) = (interpolations, after_target);
+        token
+    }
+
     fn make_token(&self, id: TokenType) -> Token {
         Token {
             id,
             lexeme: self.make_lexeme(),
             line: self.line,
+            column: self.start_column,
+            span: self.make_span(),
         }
     }
 
@@ -97,6 +205,36 @@ impl Lexer {
             id: TokenType::Error,
             lexeme: message,
             line: self.line,
+            column: self.start_column,
+            span: self.make_span(),
+        }
+    }
+
+    fn unterminated_string(&mut self) -> Token {
+        // A quote inside an expression that is never closed starts a string
+        // that runs to the end, so the expression is to blame
+        match self.interpolations.pop() {
+            Some(interpolation) => self.unterminated_interpolation(interpolation),
+            None => self.make_error(String::from("Unterminated string")),
+        }
+    }
+
+    /// Reports the `{` of an embedded expression that the source ends in.
+    /// The enclosing expressions are dropped along with it, so that the
+    /// error is only reported once.
+    fn unterminated_interpolation(&mut self, interpolation: OpenInterpolation) -> Token {
+        self.interpolations.clear();
+        Token {
+            id: TokenType::Error,
+            lexeme: String::from("Unterminated interpolation"),
+            ..interpolation.brace
+        }
+    }
+
+    fn make_span(&self) -> Span {
+        Span {
+            start: self.offsets[self.start],
+            end: self.offsets[self.curr],
         }
     }
 
@@ -121,11 +259,66 @@ impl Lexer {
     }
 
     fn make_newline(&mut self) -> Token {
+        let token = self.make_token(TokenType::Newline);
         self.line += 1;
-        self.make_token(TokenType::Newline)
+        token
     }
 
+    /// Lexes a string up to the closing quote, or up to the `{` of an
+    /// embedded expression. In that case the lexer goes back to lexing
+    /// normal tokens, and the `}` that closes the expression resumes the
+    /// string. Both quote styles embed expressions, and `\{` writes a
+    /// literal brace. Escape sequences are only skipped over here so that
+    /// `\'` and `\{` do not end the string; the compiler decodes them.
     fn make_string(&mut self, quote: char) -> Token {
+        while self.peek() != quote && !self.at_end() {
+            match self.peek() {
+                '\\' => {
+                    self.next();
+                    if self.peek() == 'u' && self.peek_next() == '{' {
+                        while self.peek() != '}' && self.peek() != quote && !self.at_end() {
+                            self.next();
+                        }
+                    }
+                    if self.at_end() {
+                        break;
+                    }
+                }
+                '{' => {
+                    let column = self.curr - self.line_start + 1;
+                    let start = self.offsets[self.curr];
+                    self.next();
+                    let brace = Token {
+                        id: TokenType::LeftBrace,
+                        lexeme: String::from("{"),
+                        line: self.line,
+                        column,
+                        span: Span {
+                            start,
+                            end: start + 1,
+                        },
+                    };
+                    self.interpolations.push(OpenInterpolation {
+                        quote,
+                        depth: 0,
+                        brace,
+                    });
+                    return self.make_token(TokenType::Interpolation);
+                }
+                '\n' => self.line += 1,
+                _ => (),
+            }
+            self.next();
+        }
+
+        if self.at_end() {
+            return self.unterminated_string();
+        }
+        self.next();
+        self.make_token(TokenType::Str)
+    }
+
+    fn make_raw_string(&mut self, quote: char) -> Token {
         while self.peek() != quote && !self.at_end() {
             if self.peek() == '\n' {
                 self.line += 1;
@@ -134,20 +327,20 @@ impl Lexer {
         }
 
         if self.at_end() {
-            return self.make_error(String::from("Unterminated string"));
+            return self.unterminated_string();
         }
         self.next();
-        self.make_token(TokenType::Str)
+        self.make_token(TokenType::RawStr)
     }
 
     fn make_number(&mut self) -> Token {
-        while self.peek().is_digit(10) {
+        while self.peek().is_ascii_digit() {
             self.next();
         }
 
-        let id = if self.peek() == '.' && self.peek_next().is_digit(10) {
+        let id = if self.peek() == '.' && self.peek_next().is_ascii_digit() {
             self.next();
-            while self.peek().is_digit(10) {
+            while self.peek().is_ascii_digit() {
                 self.next();
             }
             TokenType::Float
@@ -159,7 +352,7 @@ impl Lexer {
     }
 
     fn make_identifier(&mut self) -> Token {
-        while self.peek().is_alphabetic() || self.peek() == '_' || self.peek().is_digit(10) {
+        while self.peek().is_alphabetic() || self.peek() == '_' || self.peek().is_ascii_digit() {
             self.next();
         }
 
@@ -190,7 +383,11 @@ impl Lexer {
 
     fn next(&mut self) -> char {
         self.curr += 1;
-        self.chars[self.curr - 1]
+        let curr = self.chars[self.curr - 1];
+        if curr == '\n' {
+            self.line_start = self.curr;
+        }
+        curr
     }
 
     fn peek(&self) -> char {
@@ -213,7 +410,7 @@ impl Lexer {
 #[cfg(test)]
 mod tests {
     use super::{
-        Lexer,
+        Lexer, Span,
         TokenType::{self, *},
     };
 
@@ -235,7 +432,7 @@ mod tests {
     #[test]
     fn unknown_chars() {
         let expected = vec![Identifier, Error, Error, Bang, Identifier, Error, Eof];
-        let actual = lex("hello~ @! test &");
+        let actual = lex("hello$ @! test `");
         assert_eq!(expected, actual);
     }
 
@@ -262,9 +459,144 @@ mod tests {
         let actual = lex("22 / 2 + 42 * 1 - -4");
         assert_eq!(expected, actual);
 
+        let expected = vec![
+            Int, Percent, Int, StarStar, Int, TildeSlash, Int, Star, Tilde, Int, Eof,
+        ];
+        let actual = lex("7 % 2 ** 3 ~/ 2 * ~1");
+        assert_eq!(expected, actual);
+
+        let expected = vec![
+            Int,
+            Ampersand,
+            Int,
+            Pipe,
+            Int,
+            Caret,
+            Int,
+            LessLess,
+            Int,
+            GreaterGreater,
+            Int,
+            LessEqual,
+            Int,
+            Eof,
+        ];
+        let actual = lex("1 & 2 | 3 ^ 4 << 5 >> 6 <= 7");
+        assert_eq!(expected, actual);
+
+        let expected = vec![
+            Identifier,
+            PlusEqual,
+            Int,
+            MinusEqual,
+            Int,
+            StarEqual,
+            Int,
+            SlashEqual,
+            Int,
+            PercentEqual,
+            Int,
+            Eof,
+        ];
+        let actual = lex("x += 1 -= 2 *= 3 /= 4 %= 5");
+        assert_eq!(expected, actual);
+
+        let expected = vec![
+            Identifier,
+            PlusPlus,
+            RightBracket,
+            MinusMinus,
+            Minus,
+            Minus,
+            Identifier,
+            Eof,
+        ];
+        let actual = lex("i++ ]-- --x");
+        assert_eq!(expected, actual);
+
         let expected = vec![Float, Star, LeftParen, Int, Plus, Float, RightParen, Eof];
         let actual = lex("5.5 * (2 + 1.0)");
         assert_eq!(expected, actual);
+
+        let expected = vec![
+            Identifier, LeftParen, RightParen, Question, Dot, Identifier, Eof,
+        ];
+        let actual = lex("f()?.x");
+        assert_eq!(expected, actual);
+
+        let expected = vec![
+            Identifier,
+            LeftBracket,
+            Int,
+            RightBracket,
+            Equal,
+            LeftBracket,
+            Int,
+            Comma,
+            Int,
+            RightBracket,
+            Eof,
+        ];
+        let actual = lex("xs[0] = [1, 2]");
+        assert_eq!(expected, actual);
+
+        let expected = vec![
+            LeftBrace, Str, Colon, Int, RightBrace, Newline, Str, In, Identifier, Eof,
+        ];
+        let actual = lex("{'a': 1}\n'a' in m");
+        assert_eq!(expected, actual);
+
+        let expected = vec![
+            For, Identifier, In, Int, DotDot, Identifier, Dot, Identifier, Eof,
+        ];
+        let actual = lex("for i in 0..xs.len");
+        assert_eq!(expected, actual);
+
+        let expected = vec![
+            Fun, Identifier, LeftParen, Identifier, Colon, Int, Comma, Ellipsis, Identifier,
+            RightParen, Eof,
+        ];
+        let actual = lex("fun f(a: 1, ...rest)");
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn positions() {
+        let mut lexer = Lexer::new("var é = 'ö'\n  print é");
+        let mut positions = Vec::new();
+        loop {
+            let token = lexer.lex_token();
+            positions.push((token.line, token.column, token.span.start, token.span.end));
+            if token.id == Eof {
+                break;
+            }
+        }
+
+        let expected = vec![
+            (1, 1, 0, 3),
+            (1, 5, 4, 6),
+            (1, 7, 7, 8),
+            (1, 9, 9, 13),
+            (1, 12, 13, 14),
+            (2, 3, 16, 21),
+            (2, 9, 22, 24),
+            (2, 10, 24, 24),
+        ];
+        assert_eq!(expected, positions);
+    }
+
+    #[test]
+    fn annotations() {
+        let expected = vec![
+            Fun, Identifier, LeftParen, Identifier, Colon, Identifier, RightParen, Arrow,
+            Identifier, Eof,
+        ];
+        let actual = lex("fun f(a: int) -> str");
+        assert_eq!(expected, actual);
+
+        let expected = vec![Int, Minus, Int, Minus, Minus, Int, Eof];
+        let actual = lex("1-2 - -3");
+        assert_eq!(expected, actual);
     }
 
     #[test]
@@ -276,6 +608,32 @@ mod tests {
         let expected = vec![Str, Eof];
         let actual = lex("\"double\"");
         assert_eq!(expected, actual);
+
+        let expected = vec![Str, Str, Eof];
+        let actual = lex(r"'it\'s' 'back\\'");
+        assert_eq!(expected, actual);
+
+        let expected = vec![
+            Interpolation,
+            Identifier,
+            Plus,
+            Int,
+            Interpolation,
+            LeftBrace,
+            RightBrace,
+            Str,
+            Eof,
+        ];
+        let actual = lex("'a {b + 1} c {{}} d'");
+        assert_eq!(expected, actual);
+
+        let expected = vec![Str, Str, Eof];
+        let actual = lex(r"'\{not} \u{48}' '}'");
+        assert_eq!(expected, actual);
+
+        let expected = vec![RawStr, RawStr, Identifier, Eof];
+        let actual = lex(r#"r'C:\dir\' r"raw" r"#);
+        assert_eq!(expected, actual);
     }
 
     #[test]
@@ -287,6 +645,30 @@ mod tests {
         let expected = vec![Error, Eof];
         let actual = lex("\"nope");
         assert_eq!(expected, actual);
+
+        let expected = vec![Error, Eof];
+        let actual = lex(r"'escaped\'");
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn unterminated_interpolations() {
+        let mut lexer = Lexer::new("x = \"{\"");
+        let ids: Vec<TokenType> = (0..3).map(|_| lexer.lex_token().id).collect();
+        assert_eq!(vec![Identifier, Equal, Interpolation], ids);
+
+        // The quote starts a string inside the expression, so the error
+        // points at the `{` that is never closed
+        let error = lexer.lex_token();
+        assert_eq!(Error, error.id);
+        assert_eq!("Unterminated interpolation", error.lexeme);
+        assert_eq!((1, 6), (error.line, error.column));
+        assert_eq!(Span { start: 5, end: 6 }, error.span);
+        assert_eq!(Eof, lexer.lex_token().id);
+
+        let expected = vec![Interpolation, Int, Error, Eof];
+        let actual = lex("'{1");
+        assert_eq!(expected, actual);
     }
 
     #[test]
@@ -306,11 +688,14 @@ mod tests {
     #[test]
     fn keywords() {
         let expected = vec![
-            Or, And, Not, If, Else, While, For, Var, Let, Fun, Return, Class, Super, SelfKw, Print,
-            Do, End, Eof,
+            Or, And, Not, If, Then, Else, While, For, Var, Let, In, Fun, Return, Break, Continue,
+            Class, Super, SelfKw, Print, Do, End, Try, Catch, Throw, Match, Case, SomeKw, NoneKw,
+            OkKw, ErrKw, Eof,
         ];
-        let actual =
-            lex("or and not if else while for var let fun return class super self print do end");
+        let actual = lex(
+            "or and not if then else while for var let in fun return break continue class super \
+             self print do end try catch throw match case Some None Ok Err",
+        );
         assert_eq!(expected, actual);
     }
 
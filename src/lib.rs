@@ -0,0 +1,50 @@
+//! Flowim, a statically typed scripting language, embedded in Rust.
+//!
+//! ```
+//! use flowim::{Value, VM};
+//!
+//! let mut vm = VM::new();
+//! vm.define_native("double", 1, |args| match &args[0] {
+//!     Value::Int(n) => n
+//!         .checked_mul(2)
+//!         .map(Value::Int)
+//!         .ok_or_else(|| String::from("`double` overflowed")),
+//!     _ => Err(String::from("`double` expects an `int`")),
+//! });
+//! vm.set_global("base", Value::from(20));
+//!
+//! let script = flowim::compile("fun answer() return double(base) + 2 end").unwrap();
+//! vm.run(script).unwrap();
+//!
+//! let answer = vm.get_global("answer").unwrap();
+//! assert_eq!("42", vm.call(&answer, &[]).unwrap().to_string());
+//! ```
+
+mod ast;
+mod bigint;
+mod checker;
+mod chunk;
+mod compiler;
+mod lexer;
+mod natives;
+mod objects;
+mod parser;
+mod result;
+mod token;
+mod types;
+mod vm;
+
+pub use bigint::BigInt;
+pub use chunk::{Value, Variant};
+pub use objects::{
+    BoundMethod, Class, Closure, Exception, Function, Instance, Key, Map, NativeFunction, ValueIter,
+};
+pub use result::{Diagnostic, Label, LangError, Severity, TraceFrame};
+pub use token::Span;
+pub use vm::VM;
+
+/// Compiles a script into a function that `VM::run` can run. Fails with every
+/// compile error in the script.
+pub fn compile(source: &str) -> Result<Function, LangError> {
+    compiler::compile(source)
+}
@@ -1,19 +1,9 @@
-mod chunk;
-mod compiler;
-mod lexer;
-mod objects;
-mod result;
-mod token;
-mod vm;
-
-use result::LangError::{self, *};
+use flowim::{LangError, VM};
 use std::{
-    collections::HashMap,
     env, fs,
     io::{self, Write},
     process,
 };
-use vm::{GlobalsType, VM};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -30,28 +20,29 @@ fn main() {
 
 fn check_result<T>(result: Result<T, LangError>) -> T {
     match result {
-        Err(CompileError) => process::exit(65),
-        Err(RuntimeError) => process::exit(70),
+        Err(LangError::CompileError(_)) => process::exit(65),
+        Err(LangError::RuntimeError { .. }) => process::exit(70),
         Ok(output) => output,
     }
 }
 
-fn run_code(code: &str, globals: GlobalsType) -> Result<GlobalsType, LangError> {
-    let tokens = compiler::compile(code);
-    match tokens {
-        Ok(function) => VM::new(globals).run(function),
-        Err(error) => Err(error),
+/// Runs `code`, which was read from `path`, and prints any error.
+fn run_code(code: &str, path: &str, vm: &mut VM) -> Result<(), LangError> {
+    let result = flowim::compile(code).and_then(|function| vm.run(function));
+    if let Err(error) = &result {
+        eprintln!("{}", error.render(code, path));
     }
+    result
 }
 
 fn run_file(path: &str) {
     let code = fs::read_to_string(path).expect("Could not read test file");
-    let result = run_code(&code, HashMap::new());
+    let result = run_code(&code, path, &mut VM::new());
     check_result(result);
 }
 
 fn repl() {
-    let mut globals = HashMap::new();
+    let mut vm = VM::new();
     loop {
         print!(">>> ");
         io::stdout().flush().unwrap();
@@ -63,8 +54,6 @@ fn repl() {
         if line.is_empty() {
             continue;
         }
-        if let Ok(new_globals) = run_code(&line, globals.clone()) {
-            globals = new_globals;
-        }
+        let _ = run_code(&line, "<repl>", &mut vm);
     }
 }
@@ -0,0 +1,118 @@
+use crate::{bigint::BigInt, chunk::Value, types::Type, vm::VM};
+use std::{
+    io::{self, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Registers the functions every program can call without defining them.
+pub fn define_builtins(vm: &mut VM) {
+    vm.define_native("len", 1, len);
+    vm.define_native("str", 1, str);
+    vm.define_native("int", 1, int);
+    vm.define_native("float", 1, float);
+    vm.define_native("type", 1, type_name);
+    vm.define_native("clock", 0, clock);
+    vm.define_native("input", 1, input);
+}
+
+fn expected(function: &str, expected: &str, value: &Value) -> String {
+    format!(
+        "`{}` expects {}, but found `{}`",
+        function,
+        expected,
+        Type::of(value)
+    )
+}
+
+fn len(args: &[Value]) -> Result<Value, String> {
+    let len = match &args[0] {
+        Value::Str(string) => string.chars().count(),
+        Value::List(list) => list.borrow().len(),
+        Value::Map(map) => map.borrow().len(),
+        Value::Range(start, end) => {
+            return Ok(match end.checked_sub(*start) {
+                Some(len) => Value::Int(len.max(0)),
+                // The range is longer than the largest `int`
+                None if end > start => Value::from(&BigInt::from(*end) - &BigInt::from(*start)),
+                None => Value::Int(0),
+            });
+        }
+        other => return Err(expected("len", "a `str`, `list`, `map` or `range`", other)),
+    };
+    Ok(Value::Int(len as isize))
+}
+
+fn str(args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Str(args[0].to_string()))
+}
+
+fn int(args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::Int(_) | Value::BigInt(_) => Ok(args[0].clone()),
+        Value::Bool(value) => Ok(Value::Int(*value as isize)),
+        Value::Float(value) if !value.is_finite() => {
+            Err(format!("Cannot convert {} to an `int`", value))
+        }
+        Value::Float(value) if value.abs() < isize::MAX as f64 => {
+            Ok(Value::Int(value.trunc() as isize))
+        }
+        Value::Float(value) => {
+            // Large floats are whole numbers, so their decimal form parses
+            let big = BigInt::parse(&format!("{:.0}", value.trunc())).unwrap();
+            Ok(Value::from(big))
+        }
+        Value::Str(string) => {
+            let text = string.trim();
+            let text = text.strip_prefix('+').unwrap_or(text);
+            match BigInt::parse(text) {
+                Some(big) => Ok(Value::from(big)),
+                None => Err(format!("Cannot convert '{}' to an `int`", string)),
+            }
+        }
+        other => Err(expected("int", "a number, `bool` or `str`", other)),
+    }
+}
+
+fn float(args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::Float(_) => Ok(args[0].clone()),
+        Value::Int(value) => Ok(Value::Float(*value as f64)),
+        Value::BigInt(value) => Ok(Value::Float(value.to_f64())),
+        Value::Bool(value) => Ok(Value::Float(*value as u8 as f64)),
+        Value::Str(string) => match string.trim().parse() {
+            Ok(value) => Ok(Value::Float(value)),
+            Err(_) => Err(format!("Cannot convert '{}' to a `float`", string)),
+        },
+        other => Err(expected("float", "a number, `bool` or `str`", other)),
+    }
+}
+
+fn type_name(args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Str(Type::of(&args[0]).to_string()))
+}
+
+fn clock(_args: &[Value]) -> Result<Value, String> {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|error| error.to_string())?;
+    Ok(Value::Float(elapsed.as_secs_f64()))
+}
+
+/// Prints the prompt and reads a line from standard input, without its line
+/// ending. Returns an empty string at the end of the input.
+fn input(args: &[Value]) -> Result<Value, String> {
+    let prompt = match &args[0] {
+        Value::Str(prompt) => prompt,
+        other => return Err(expected("input", "a `str` prompt", other)),
+    };
+    print!("{}", prompt);
+    io::stdout().flush().map_err(|error| error.to_string())?;
+
+    let mut line = String::new();
+    io::stdin()
+        .read_line(&mut line)
+        .map_err(|error| error.to_string())?;
+    let len = line.trim_end_matches(['\n', '\r']).len();
+    line.truncate(len);
+    Ok(Value::Str(line))
+}
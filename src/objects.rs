@@ -1,25 +1,67 @@
-use crate::chunk::Chunk;
-use std::fmt::{self, Display};
+use crate::{
+    bigint::BigInt,
+    chunk::{Chunk, Value},
+    result::{LangError, TraceFrame},
+};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::{self, Display},
+    ptr,
+    rc::Rc,
+};
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum FunctionType {
     Function,
+    /// An anonymous function expression, whose body is a block that
+    /// returns its value.
+    Lambda,
+    Initializer,
+    Method,
     Script,
 }
 
+/// Where a closure finds a captured variable when it is created: either a
+/// local slot of the enclosing function or one of the enclosing closure's
+/// own upvalues.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UpvalueIndex {
+    pub index: usize,
+    pub is_local: bool,
+}
+
 #[derive(Clone, PartialEq)]
 pub struct Function {
+    /// The number of positional parameters, not counting the rest parameter.
     pub arity: usize,
+    /// The names of the positional parameters, for calls with named arguments.
+    pub params: Vec<String>,
+    /// How many trailing positional parameters have a default value.
+    pub defaults: usize,
+    /// Whether a rest parameter collects the extra arguments into a list.
+    pub variadic: bool,
     pub chunk: Chunk,
     pub name: String,
+    pub upvalues: Vec<UpvalueIndex>,
+}
+
+impl Default for Function {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Function {
     pub fn new() -> Self {
         Function {
             arity: 0,
+            params: Vec::new(),
+            defaults: 0,
+            variadic: false,
             chunk: Chunk::new(),
             name: String::from("<script>"),
+            upvalues: Vec::new(),
         }
     }
 }
@@ -29,3 +71,329 @@ impl Display for Function {
         write!(format, "<fun {}>", self.name)
     }
 }
+
+/// A captured variable. It points at a stack slot while the variable is
+/// still in scope, and owns the value once that slot has been popped.
+#[derive(PartialEq)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+pub struct Closure {
+    pub function: Rc<Function>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    /// Functions that give the default parameter values, given the values of
+    /// the parameters before them.
+    pub defaults: Vec<Value>,
+}
+
+impl Closure {
+    pub fn new(function: Rc<Function>) -> Self {
+        Closure {
+            function,
+            upvalues: Vec::new(),
+            defaults: Vec::new(),
+        }
+    }
+}
+
+impl PartialEq for Closure {
+    fn eq(&self, other: &Self) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+impl Display for Closure {
+    fn fmt(&self, format: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(format, "{}", self.function)
+    }
+}
+
+/// The Rust implementation of a built-in function. It receives the call's
+/// arguments and returns the result or an error message.
+pub type NativeFn = dyn Fn(&[Value]) -> Result<Value, String>;
+
+/// A function implemented in Rust and registered with `VM::define_native`.
+pub struct NativeFunction {
+    pub name: String,
+    pub arity: usize,
+    pub function: Box<NativeFn>,
+}
+
+impl PartialEq for NativeFunction {
+    fn eq(&self, other: &Self) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+impl Display for NativeFunction {
+    fn fmt(&self, format: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(format, "<native fun {}>", self.name)
+    }
+}
+
+pub struct Class {
+    pub name: String,
+    pub methods: HashMap<String, Rc<Closure>>,
+}
+
+impl Class {
+    pub fn new(name: String) -> Self {
+        Class {
+            name,
+            methods: HashMap::new(),
+        }
+    }
+}
+
+impl PartialEq for Class {
+    fn eq(&self, other: &Self) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+impl Display for Class {
+    fn fmt(&self, format: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(format, "<class {}>", self.name)
+    }
+}
+
+pub struct Instance {
+    pub class: Rc<RefCell<Class>>,
+    pub fields: HashMap<String, Value>,
+}
+
+impl Instance {
+    pub fn new(class: Rc<RefCell<Class>>) -> Self {
+        Instance {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+}
+
+impl PartialEq for Instance {
+    fn eq(&self, other: &Self) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+impl Display for Instance {
+    fn fmt(&self, format: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(format, "<{} instance>", self.class.borrow().name)
+    }
+}
+
+/// A method that has been read from an instance, remembering the instance
+/// so that `self` is bound when the method is eventually called.
+#[derive(PartialEq)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Rc<Closure>,
+}
+
+impl Display for BoundMethod {
+    fn fmt(&self, format: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(format, "{}", self.method)
+    }
+}
+
+/// An error as a value, for `catch` blocks. It is either thrown by the
+/// script or raised by the VM, like a division by zero.
+pub struct Exception {
+    pub message: String,
+    /// The calls that were active where it was raised, innermost first.
+    pub trace: Vec<TraceFrame>,
+}
+
+impl Exception {
+    /// The error to report when nothing catches this one.
+    pub fn to_error(&self) -> LangError {
+        LangError::RuntimeError {
+            message: self.message.clone(),
+            trace: self.trace.clone(),
+        }
+    }
+}
+
+impl PartialEq for Exception {
+    fn eq(&self, other: &Self) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+impl Display for Exception {
+    fn fmt(&self, format: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(format, "{}", self.message)
+    }
+}
+
+/// A value that can be used as a map key.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Key {
+    Bool(bool),
+    Int(isize),
+    BigInt(Rc<BigInt>),
+    Str(String),
+}
+
+impl Key {
+    pub fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Bool(value) => Some(Key::Bool(*value)),
+            Value::Int(value) => Some(Key::Int(*value)),
+            Value::BigInt(value) => Some(Key::BigInt(value.clone())),
+            Value::Str(value) => Some(Key::Str(value.clone())),
+            _ => None,
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        match self {
+            Key::Bool(value) => Value::Bool(*value),
+            Key::Int(value) => Value::Int(*value),
+            Key::BigInt(value) => Value::BigInt(value.clone()),
+            Key::Str(value) => Value::Str(value.clone()),
+        }
+    }
+}
+
+/// A hash map that remembers the order its keys were inserted in, so that
+/// printing and iterating over it is deterministic.
+#[derive(Default)]
+pub struct Map {
+    indices: HashMap<Key, usize>,
+    entries: Vec<(Key, Value)>,
+}
+
+impl Map {
+    pub fn new() -> Self {
+        Map::default()
+    }
+
+    pub fn get(&self, key: &Key) -> Option<&Value> {
+        self.indices.get(key).map(|&index| &self.entries[index].1)
+    }
+
+    pub fn insert(&mut self, key: Key, value: Value) {
+        match self.indices.get(&key) {
+            Some(&index) => self.entries[index].1 = value,
+            None => {
+                self.indices.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    pub fn contains_key(&self, key: &Key) -> bool {
+        self.indices.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(Key, Value)> {
+        self.entries.iter()
+    }
+}
+
+impl PartialEq for Map {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len()
+            && self
+                .iter()
+                .all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+/// The state of a `for ... in` loop over a value. `Iter` opcodes turn an
+/// iterable value into one of these, and `ForIter` advances it. A new kind
+/// of iterable, such as a user-defined class, only needs a variant here and
+/// a case in `ValueIter::new`.
+pub enum ValueIter {
+    List {
+        list: Rc<RefCell<Vec<Value>>>,
+        index: usize,
+    },
+    Chars {
+        chars: Vec<char>,
+        index: usize,
+    },
+    Range {
+        next: isize,
+        end: isize,
+    },
+    /// The keys of a map, collected when the loop starts so that the map
+    /// can be changed inside the loop.
+    Keys {
+        keys: Vec<Value>,
+        index: usize,
+    },
+}
+
+impl ValueIter {
+    pub fn new(value: &Value) -> Option<Self> {
+        Some(match value {
+            Value::List(list) => ValueIter::List {
+                list: list.clone(),
+                index: 0,
+            },
+            Value::Str(string) => ValueIter::Chars {
+                chars: string.chars().collect(),
+                index: 0,
+            },
+            Value::Range(start, end) => ValueIter::Range {
+                next: *start,
+                end: *end,
+            },
+            Value::Map(map) => ValueIter::Keys {
+                keys: map.borrow().iter().map(|(key, _)| key.to_value()).collect(),
+                index: 0,
+            },
+            _ => return None,
+        })
+    }
+}
+
+impl Iterator for ValueIter {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        match self {
+            ValueIter::List { list, index } => {
+                let item = list.borrow().get(*index).cloned();
+                *index += 1;
+                item
+            }
+            ValueIter::Chars { chars, index } => {
+                let item = chars.get(*index).map(|c| Value::Str(c.to_string()));
+                *index += 1;
+                item
+            }
+            ValueIter::Range { next, end } => {
+                if next >= end {
+                    return None;
+                }
+                *next += 1;
+                Some(Value::Int(*next - 1))
+            }
+            ValueIter::Keys { keys, index } => {
+                let item = keys.get(*index).cloned();
+                *index += 1;
+                item
+            }
+        }
+    }
+}
+
+impl PartialEq for ValueIter {
+    fn eq(&self, other: &Self) -> bool {
+        ptr::eq(self, other)
+    }
+}
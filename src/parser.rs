@@ -0,0 +1,1555 @@
+use crate::{
+    ast::{Block, Case, Class, Expr, ExprKind, Fun, Match, Param, Pattern, Stmt, TypeExpr},
+    bigint::BigInt,
+    chunk::{OpCode, Value, Variant},
+    lexer::Lexer,
+    objects::FunctionType,
+    result::{Diagnostic, Severity},
+    token::{Span, Token, TokenType},
+    types::{self, Type},
+};
+use std::{collections::HashMap, mem};
+
+#[derive(PartialEq, PartialOrd)]
+enum Precedence {
+    None,
+    Assignment,
+    Or,
+    And,
+    Equality,
+    Comparison,
+    Range,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Shift,
+    Term,
+    Factor,
+    Unary,
+    Exponent,
+    Call,
+    Primary,
+}
+
+impl Precedence {
+    fn next(&self) -> Self {
+        use Precedence::*;
+        match self {
+            None => Assignment,
+            Assignment => Or,
+            Or => And,
+            And => Equality,
+            Equality => Comparison,
+            Comparison => Range,
+            Range => BitOr,
+            BitOr => BitXor,
+            BitXor => BitAnd,
+            BitAnd => Shift,
+            Shift => Term,
+            Term => Factor,
+            Factor => Unary,
+            Unary => Exponent,
+            Exponent => Call,
+            Call => Primary,
+            Primary => panic!("No rule higher than Primary"),
+        }
+    }
+}
+
+type PrefixFn = Option<fn(&mut Parser, can_assign: bool) -> ExprKind>;
+type InfixFn = Option<fn(&mut Parser, left: Expr, can_assign: bool) -> ExprKind>;
+
+struct ParseRule {
+    prefix: PrefixFn,
+    infix: InfixFn,
+    precedence: Precedence,
+}
+
+struct Parser {
+    lexer: Lexer,
+    curr: Token,
+    prev: Token,
+    diagnostics: Vec<Diagnostic>,
+    panic_mode: bool,
+    rules: HashMap<TokenType, ParseRule>,
+}
+
+impl Parser {
+    fn new(code: &str) -> Self {
+        use Precedence as P;
+        use TokenType::*;
+
+        let rule = |prefix, infix, precedence| ParseRule {
+            prefix,
+            infix,
+            precedence,
+        };
+
+        let rules = HashMap::from([
+            (
+                LeftParen,
+                rule(Some(Self::group), Some(Self::call), P::Call),
+            ),
+            (Dot, rule(None, Some(Self::dot), P::Call)),
+            (
+                LeftBracket,
+                rule(Some(Self::list), Some(Self::index), P::Call),
+            ),
+            (LeftBrace, rule(Some(Self::map), None, P::None)),
+            (Minus, rule(Some(Self::unary), Some(Self::binary), P::Term)),
+            (Plus, rule(None, Some(Self::binary), P::Term)),
+            (Slash, rule(None, Some(Self::binary), P::Factor)),
+            (Star, rule(None, Some(Self::binary), P::Factor)),
+            (Percent, rule(None, Some(Self::binary), P::Factor)),
+            (TildeSlash, rule(None, Some(Self::binary), P::Factor)),
+            (StarStar, rule(None, Some(Self::binary), P::Exponent)),
+            (Tilde, rule(Some(Self::unary), None, P::None)),
+            (Ampersand, rule(None, Some(Self::binary), P::BitAnd)),
+            (Pipe, rule(None, Some(Self::binary), P::BitOr)),
+            (Caret, rule(None, Some(Self::binary), P::BitXor)),
+            (LessLess, rule(None, Some(Self::binary), P::Shift)),
+            (GreaterGreater, rule(None, Some(Self::binary), P::Shift)),
+            (Bool, rule(Some(Self::bool), None, P::None)),
+            (Int, rule(Some(Self::int), None, P::None)),
+            (Float, rule(Some(Self::float), None, P::None)),
+            (Str, rule(Some(Self::string), None, P::None)),
+            (RawStr, rule(Some(Self::raw_string), None, P::None)),
+            (
+                Interpolation,
+                rule(Some(Self::interpolation), None, P::None),
+            ),
+            (Not, rule(Some(Self::unary), None, P::None)),
+            (And, rule(None, Some(Self::and_op), P::And)),
+            (Or, rule(None, Some(Self::or_op), P::Or)),
+            (BangEqual, rule(None, Some(Self::binary), P::Equality)),
+            (EqualEqual, rule(None, Some(Self::binary), P::Equality)),
+            (Greater, rule(None, Some(Self::binary), P::Comparison)),
+            (GreaterEqual, rule(None, Some(Self::binary), P::Comparison)),
+            (Less, rule(None, Some(Self::binary), P::Comparison)),
+            (LessEqual, rule(None, Some(Self::binary), P::Comparison)),
+            (In, rule(None, Some(Self::contains), P::Comparison)),
+            (DotDot, rule(None, Some(Self::binary), P::Range)),
+            (Identifier, rule(Some(Self::variable), None, P::None)),
+            (SelfKw, rule(Some(Self::self_kw), None, P::None)),
+            (Super, rule(Some(Self::super_kw), None, P::None)),
+            (If, rule(Some(Self::if_expression), None, P::None)),
+            (Fun, rule(Some(Self::lambda), None, P::None)),
+            (Do, rule(Some(Self::do_expression), None, P::None)),
+            (Match, rule(Some(Self::match_expression), None, P::None)),
+            (SomeKw, rule(Some(Self::wrap), None, P::None)),
+            (NoneKw, rule(Some(Self::none), None, P::None)),
+            (OkKw, rule(Some(Self::wrap), None, P::None)),
+            (ErrKw, rule(Some(Self::wrap), None, P::None)),
+            (Question, rule(None, Some(Self::propagate), P::Call)),
+        ]);
+
+        Parser {
+            lexer: Lexer::new(code),
+            curr: Token {
+                id: TokenType::Eof,
+                lexeme: String::new(),
+                line: 1,
+                column: 1,
+                span: Span::default(),
+            },
+            prev: Token {
+                id: TokenType::Eof,
+                lexeme: String::new(),
+                line: 1,
+                column: 1,
+                span: Span::default(),
+            },
+            diagnostics: Vec::new(),
+            panic_mode: false,
+            rules,
+        }
+    }
+
+    fn parse(&mut self) -> Vec<Stmt> {
+        self.next();
+        self.eat_delimit();
+        let mut program = Vec::new();
+        while !self.matches(TokenType::Eof) {
+            program.push(self.declaration());
+        }
+        program
+    }
+
+    fn next(&mut self) {
+        self.prev = self.curr.clone();
+
+        loop {
+            self.curr = self.lexer.lex_token();
+            if self.curr.id != TokenType::Error {
+                break;
+            }
+            self.error_curr(&self.curr.lexeme.clone());
+        }
+    }
+
+    fn eat(&mut self, id: TokenType, message: &str) {
+        if self.curr.id == id {
+            self.next();
+            return;
+        }
+        self.error_curr(message);
+    }
+
+    fn eat_delimit(&mut self) {
+        while self.curr.id == TokenType::Semicolon || self.curr.id == TokenType::Newline {
+            self.next();
+        }
+    }
+
+    /// Parses a declaration or a statement. One with a syntax error in it is
+    /// skipped up to where the next one seems to start.
+    fn declaration(&mut self) -> Stmt {
+        let stmt = if self.at_terminator() {
+            // A terminator without a block to close. Consume it, or
+            // `synchronize` would stop at it forever.
+            let message = format!("Unexpected '{}'", self.curr.lexeme);
+            self.error_curr(&message);
+            self.next();
+            Stmt::Error
+        } else if self.matches(TokenType::Class) {
+            self.class_declaration()
+        } else if self.at_fun_declaration() {
+            self.next();
+            self.fun_declaration()
+        } else if self.matches(TokenType::Var) {
+            self.var_declaration(true)
+        } else if self.matches(TokenType::Let) {
+            self.var_declaration(false)
+        } else {
+            self.statement()
+        };
+
+        if self.panic_mode {
+            self.synchronize();
+            return Stmt::Broken(Box::new(stmt));
+        }
+        stmt
+    }
+
+    fn var_declaration(&mut self, mutable: bool) -> Stmt {
+        self.eat(TokenType::Identifier, "Expected a variable name");
+        let name = self.prev.clone();
+
+        let annotation = if self.matches(TokenType::Colon) {
+            Some(self.parse_type())
+        } else {
+            None
+        };
+
+        let value = if self.matches(TokenType::Equal) {
+            self.expression()
+        } else {
+            self.error_curr("Expected an expression");
+            self.error_expr()
+        };
+
+        self.eat_delimit();
+        Stmt::Var {
+            name,
+            mutable,
+            annotation,
+            value,
+        }
+    }
+
+    fn class_declaration(&mut self) -> Stmt {
+        self.eat(TokenType::Identifier, "Expected a class name");
+        let name = self.prev.clone();
+
+        let superclass = if self.matches(TokenType::Less) {
+            self.eat(TokenType::Identifier, "Expected a superclass name");
+            Some(self.prev.clone())
+        } else {
+            None
+        };
+        self.eat_delimit();
+
+        let mut methods = Vec::new();
+        while !self.check(TokenType::End) && !self.check(TokenType::Eof) {
+            if self.check(TokenType::Fun) {
+                methods.push(self.method());
+            } else {
+                self.error_curr("Expected a method declaration");
+                self.next();
+            }
+            if self.panic_mode {
+                self.synchronize();
+            }
+            self.eat_delimit();
+        }
+        self.eat(TokenType::End, "Expected 'end' after class body");
+        self.eat_delimit();
+
+        Stmt::Class(Class {
+            name,
+            superclass,
+            methods,
+        })
+    }
+
+    fn method(&mut self) -> Fun {
+        self.eat(TokenType::Fun, "Expected a method declaration");
+        self.eat(TokenType::Identifier, "Expected a method name");
+
+        let kind = if self.prev.lexeme == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(kind)
+    }
+
+    /// Whether `fun` starts a named function declaration rather than an
+    /// anonymous function expression.
+    fn at_fun_declaration(&mut self) -> bool {
+        self.check(TokenType::Fun) && self.lexer.peek_token().id != TokenType::LeftParen
+    }
+
+    /// Whether the current token ends a block. Only the code parsing that
+    /// block consumes it.
+    fn at_terminator(&self) -> bool {
+        matches!(
+            self.curr.id,
+            TokenType::End | TokenType::Else | TokenType::Catch | TokenType::Case
+        )
+    }
+
+    fn lambda(&mut self, _can_assign: bool) -> ExprKind {
+        ExprKind::Lambda(Box::new(self.function(FunctionType::Lambda)))
+    }
+
+    fn fun_declaration(&mut self) -> Stmt {
+        self.eat(TokenType::Identifier, "Expected a function name");
+        Stmt::Fun(self.function(FunctionType::Function))
+    }
+
+    fn function(&mut self, kind: FunctionType) -> Fun {
+        let mut name = self.prev.clone();
+        if kind == FunctionType::Lambda {
+            name.lexeme = format!("lambda@{}", name.line);
+        }
+
+        self.eat(TokenType::LeftParen, "Expected '(' after function name");
+
+        let mut params: Vec<Param> = Vec::new();
+        let mut rest = None;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if self.matches(TokenType::Ellipsis) {
+                    rest = Some(self.rest_parameter());
+                    break;
+                }
+
+                self.eat(TokenType::Identifier, "Expected a parameter name");
+                let name = self.prev.clone();
+
+                let annotation = if self.matches(TokenType::Colon) {
+                    Some(self.parse_type())
+                } else {
+                    None
+                };
+
+                let default = if self.matches(TokenType::Equal) {
+                    Some(self.expression())
+                } else {
+                    if params.iter().any(|param| param.default.is_some()) {
+                        self.error_at(
+                            name.clone(),
+                            "A parameter without a default value cannot follow one with a default",
+                        );
+                    }
+                    None
+                };
+
+                params.push(Param {
+                    name,
+                    annotation,
+                    default,
+                });
+
+                if !self.matches(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.eat(
+            TokenType::RightParen,
+            "Expected ')' after function parameters",
+        );
+
+        let ret = if self.matches(TokenType::Arrow) {
+            Some(self.parse_type())
+        } else {
+            None
+        };
+
+        let (body, end) = if kind == FunctionType::Lambda {
+            let body = self.value_block(&[TokenType::End]);
+            self.eat(TokenType::End, "Expected 'end' after block");
+            (body, self.prev.clone())
+        } else {
+            self.eat_delimit();
+            let stmts = self.block();
+            let end = self.prev.clone();
+            self.eat_delimit();
+            (Block { stmts, value: None }, end)
+        };
+
+        Fun {
+            kind,
+            name,
+            params,
+            rest,
+            ret,
+            body,
+            end,
+        }
+    }
+
+    /// Parses a `...name` parameter, which collects the extra arguments of a
+    /// call into a list.
+    fn rest_parameter(&mut self) -> Param {
+        self.eat(
+            TokenType::Identifier,
+            "Expected a parameter name after '...'",
+        );
+        let name = self.prev.clone();
+        let annotation = if self.matches(TokenType::Colon) {
+            Some(self.parse_type())
+        } else {
+            None
+        };
+
+        if self.check(TokenType::Comma) {
+            self.error_curr("The rest parameter must be the last parameter");
+            while !self.check(TokenType::RightParen) && !self.check(TokenType::Eof) {
+                self.next();
+            }
+        }
+        Param {
+            name,
+            annotation,
+            default: None,
+        }
+    }
+
+    fn statement(&mut self) -> Stmt {
+        let stmt = if self.matches(TokenType::Print) {
+            Stmt::Print(self.expression())
+        } else if self.matches(TokenType::Return) {
+            self.return_statement()
+        } else if self.matches(TokenType::Break) {
+            Stmt::Break(self.prev.clone())
+        } else if self.matches(TokenType::Continue) {
+            Stmt::Continue(self.prev.clone())
+        } else if self.matches(TokenType::If) {
+            self.if_statement()
+        } else if self.matches(TokenType::While) {
+            self.while_statement()
+        } else if self.matches(TokenType::For) {
+            self.for_statement()
+        } else if self.matches(TokenType::Do) {
+            self.eat_delimit();
+            Stmt::Block(self.block())
+        } else if self.matches(TokenType::Try) {
+            self.try_statement()
+        } else if self.matches(TokenType::Throw) {
+            let keyword = self.prev.clone();
+            let value = self.expression();
+            Stmt::Throw { keyword, value }
+        } else if self.matches(TokenType::Match) {
+            self.match_statement()
+        } else {
+            Stmt::Expression(self.expression())
+        };
+        self.eat_delimit();
+        stmt
+    }
+
+    fn return_statement(&mut self) -> Stmt {
+        let keyword = self.prev.clone();
+        if self.matches_delimit() {
+            return Stmt::Return {
+                keyword,
+                value: None,
+            };
+        }
+
+        let value = self.expression();
+        self.eat_delimit();
+        Stmt::Return {
+            keyword,
+            value: Some(value),
+        }
+    }
+
+    fn if_statement(&mut self) -> Stmt {
+        let condition = self.expression();
+        self.matches(TokenType::Then);
+        self.eat_delimit();
+
+        let then_branch = self.if_block();
+        let else_branch = if self.matches(TokenType::Else) {
+            Some(self.if_block())
+        } else {
+            None
+        };
+
+        Stmt::If {
+            condition,
+            then_branch,
+            else_branch,
+        }
+    }
+
+    fn while_statement(&mut self) -> Stmt {
+        let condition = self.expression();
+        self.eat_delimit();
+        let body = self.block();
+
+        Stmt::While { condition, body }
+    }
+
+    fn for_statement(&mut self) -> Stmt {
+        if self.check(TokenType::Identifier) && self.lexer.peek_token().id == TokenType::In {
+            return self.for_in_statement();
+        }
+
+        let initializer = if self.matches(TokenType::Semicolon) {
+            None
+        } else if self.matches(TokenType::Var) {
+            Some(Box::new(self.var_declaration(true)))
+        } else {
+            Some(Box::new(Stmt::Expression(self.expression())))
+        };
+
+        let condition = if !self.matches(TokenType::Semicolon) {
+            let condition = self.expression();
+            self.eat(TokenType::Semicolon, "Expected a semicolon ';'");
+            Some(condition)
+        } else {
+            None
+        };
+
+        let increment = if !self.matches_delimit() {
+            let increment = self.expression();
+            self.eat_delimit();
+            Some(increment)
+        } else {
+            None
+        };
+
+        let body = self.block();
+
+        Stmt::For {
+            initializer,
+            condition,
+            increment,
+            body,
+        }
+    }
+
+    fn for_in_statement(&mut self) -> Stmt {
+        self.eat(TokenType::Identifier, "Expected a loop variable name");
+        let name = self.prev.clone();
+        self.eat(TokenType::In, "Expected 'in' after the loop variable");
+
+        let collection = self.expression();
+        self.eat_delimit();
+        let body = self.block();
+
+        Stmt::ForIn {
+            name,
+            collection,
+            body,
+        }
+    }
+
+    fn try_statement(&mut self) -> Stmt {
+        let keyword = self.prev.clone();
+        self.eat_delimit();
+
+        let mut body = Vec::new();
+        while !self.check(TokenType::Catch)
+            && !self.check(TokenType::End)
+            && !self.check(TokenType::Eof)
+        {
+            body.push(self.declaration());
+        }
+
+        self.eat(TokenType::Catch, "Expected 'catch' after try block");
+        self.eat(
+            TokenType::Identifier,
+            "Expected a variable name after 'catch'",
+        );
+        let name = self.prev.clone();
+        self.eat_delimit();
+        let handler = self.block();
+
+        Stmt::Try {
+            keyword,
+            body,
+            name,
+            handler,
+        }
+    }
+
+    /// Parses a `match` whose cases are blocks of statements.
+    fn match_statement(&mut self) -> Stmt {
+        let keyword = self.prev.clone();
+        let subject = self.expression();
+        self.eat_delimit();
+
+        let mut cases = Vec::new();
+        while self.matches(TokenType::Case) {
+            let case = self.prev.clone();
+            let pattern = self.case_pattern();
+            let mut body = Vec::new();
+            while !self.check(TokenType::Case)
+                && !self.check(TokenType::End)
+                && !self.check(TokenType::Eof)
+            {
+                body.push(self.declaration());
+            }
+            cases.push(Case {
+                keyword: case,
+                pattern,
+                body,
+            });
+        }
+        self.eat(TokenType::End, "Expected 'end' after match cases");
+
+        Stmt::Match(Match {
+            keyword,
+            subject,
+            cases,
+        })
+    }
+
+    /// Parses the pattern of a `case`, which is `None` when it is not valid.
+    fn case_pattern(&mut self) -> Option<Pattern> {
+        let variant = match self.curr.id {
+            TokenType::SomeKw => Variant::Some,
+            TokenType::NoneKw => Variant::None,
+            TokenType::OkKw => Variant::Ok,
+            TokenType::ErrKw => Variant::Err,
+            _ => {
+                self.error_curr("Expected `Some`, `None`, `Ok` or `Err` after 'case'");
+                self.skip_group(TokenType::Newline);
+                self.panic_mode = false;
+                self.eat_delimit();
+                return None;
+            }
+        };
+        self.next();
+        let token = self.prev.clone();
+
+        let binding = if variant != Variant::None {
+            self.eat(
+                TokenType::LeftParen,
+                &format!("Expected '(' after `{}`", variant),
+            );
+            self.eat(TokenType::Identifier, "Expected a variable name");
+            let name = self.prev.clone();
+            self.eat(
+                TokenType::RightParen,
+                "Expected ')' after the variable name",
+            );
+            Some(name)
+        } else {
+            None
+        };
+        self.matches(TokenType::Then);
+        self.eat_delimit();
+
+        Some(Pattern {
+            variant,
+            token,
+            binding,
+        })
+    }
+
+    fn expression(&mut self) -> Expr {
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    fn block(&mut self) -> Vec<Stmt> {
+        let mut stmts = Vec::new();
+        while !self.check(TokenType::End) && !self.check(TokenType::Eof) {
+            stmts.push(self.declaration());
+        }
+        self.eat(TokenType::End, "Expected 'end' after block");
+        stmts
+    }
+
+    fn if_block(&mut self) -> Vec<Stmt> {
+        self.eat_delimit();
+
+        let mut stmts = Vec::new();
+        while !self.check(TokenType::Else)
+            && !self.check(TokenType::End)
+            && !self.check(TokenType::Eof)
+        {
+            stmts.push(self.declaration());
+        }
+        if self.curr.id != TokenType::Else {
+            self.eat(TokenType::End, "Expected 'end' after if block");
+        }
+        stmts
+    }
+
+    /// Parses the statements of a block up to one of `terminators`. The value
+    /// of the block is that of its last statement if that is an expression.
+    fn value_block(&mut self, terminators: &[TokenType]) -> Block {
+        let mut stmts = Vec::new();
+        let mut value = None;
+        loop {
+            self.eat_delimit();
+            if terminators.contains(&self.curr.id) || self.check(TokenType::Eof) {
+                break;
+            }
+
+            use TokenType::*;
+            if matches!(
+                self.curr.id,
+                Class
+                    | Var
+                    | Let
+                    | Print
+                    | Return
+                    | While
+                    | For
+                    | Break
+                    | Continue
+                    | Try
+                    | Throw
+                    | End
+                    | Else
+                    | Catch
+                    | Case
+            ) || self.at_fun_declaration()
+            {
+                stmts.push(self.declaration());
+                continue;
+            }
+
+            let expr = self.expression();
+            if self.panic_mode {
+                self.synchronize();
+                let broken = self.error_expr();
+                stmts.push(Stmt::Broken(Box::new(Stmt::Expression(expr))));
+                if terminators.contains(&self.curr.id) {
+                    value = Some(Box::new(broken));
+                    break;
+                }
+                continue;
+            }
+            self.eat_delimit();
+            if terminators.contains(&self.curr.id) {
+                value = Some(Box::new(expr));
+                break;
+            }
+            stmts.push(Stmt::Expression(expr));
+        }
+        Block { stmts, value }
+    }
+
+    fn if_expression(&mut self, _can_assign: bool) -> ExprKind {
+        let condition = self.expression();
+        self.matches(TokenType::Then);
+
+        let then_branch = self.value_block(&[TokenType::Else, TokenType::End]);
+        let else_branch = if self.matches(TokenType::Else) {
+            Some(self.value_block(&[TokenType::End]))
+        } else {
+            None
+        };
+        self.eat(TokenType::End, "Expected 'end' after if expression");
+
+        ExprKind::If {
+            condition: Box::new(condition),
+            then_branch,
+            else_branch,
+        }
+    }
+
+    fn do_expression(&mut self, _can_assign: bool) -> ExprKind {
+        let block = self.value_block(&[TokenType::End]);
+        self.eat(TokenType::End, "Expected 'end' after block");
+        ExprKind::Do(block)
+    }
+
+    /// Parses a `match` whose cases are blocks with a value, like
+    /// `value_block`.
+    fn match_expression(&mut self, _can_assign: bool) -> ExprKind {
+        let keyword = self.prev.clone();
+        let subject = self.expression();
+        self.eat_delimit();
+
+        let mut cases = Vec::new();
+        while self.matches(TokenType::Case) {
+            let case = self.prev.clone();
+            let pattern = self.case_pattern();
+            let body = self.value_block(&[TokenType::Case, TokenType::End]);
+            cases.push(Case {
+                keyword: case,
+                pattern,
+                body,
+            });
+        }
+        self.eat(TokenType::End, "Expected 'end' after match cases");
+
+        ExprKind::Match(Box::new(Match {
+            keyword,
+            subject,
+            cases,
+        }))
+    }
+
+    /// Parses `Some(value)`, `Ok(value)` or `Err(value)`.
+    fn wrap(&mut self, _can_assign: bool) -> ExprKind {
+        let variant = match self.prev.id {
+            TokenType::SomeKw => Variant::Some,
+            TokenType::OkKw => Variant::Ok,
+            _ => Variant::Err,
+        };
+        self.eat(
+            TokenType::LeftParen,
+            &format!("Expected '(' after `{}`", variant),
+        );
+        let value = self.expression();
+        self.eat(TokenType::RightParen, "Expected ')' after the value");
+
+        ExprKind::Wrap {
+            variant,
+            value: Box::new(value),
+        }
+    }
+
+    fn none(&mut self, _can_assign: bool) -> ExprKind {
+        ExprKind::None
+    }
+
+    /// Parses the postfix `?`, which unwraps a `Some` or `Ok` value and
+    /// returns a `None` or `Err` from the function as it is.
+    fn propagate(&mut self, value: Expr, _can_assign: bool) -> ExprKind {
+        ExprKind::Propagate {
+            operator: self.prev.clone(),
+            value: Box::new(value),
+        }
+    }
+
+    fn bool(&mut self, _can_assign: bool) -> ExprKind {
+        let value = self.prev.lexeme.parse::<bool>().unwrap();
+        ExprKind::Literal(Value::Bool(value))
+    }
+
+    fn int(&mut self, _can_assign: bool) -> ExprKind {
+        let value = match self.prev.lexeme.parse::<isize>() {
+            Ok(value) => Value::Int(value),
+            // The lexer only produces digits, so this cannot fail
+            Err(_) => Value::from(BigInt::parse(&self.prev.lexeme).unwrap()),
+        };
+        ExprKind::Literal(value)
+    }
+
+    fn float(&mut self, _can_assign: bool) -> ExprKind {
+        let value = self.prev.lexeme.parse::<f64>().unwrap();
+        ExprKind::Literal(Value::Float(value))
+    }
+
+    fn string(&mut self, _can_assign: bool) -> ExprKind {
+        let token = self.prev.clone();
+        ExprKind::Literal(Value::Str(self.unescape(&token)))
+    }
+
+    fn raw_string(&mut self, _can_assign: bool) -> ExprKind {
+        let lexeme = &self.prev.lexeme;
+        ExprKind::Literal(Value::Str(lexeme[2..lexeme.len() - 1].to_string()))
+    }
+
+    /// Parses a string with embedded expressions. Each part of the string
+    /// before an expression arrives as an `Interpolation` token, and the
+    /// rest of the string after the last expression as a `Str` token.
+    fn interpolation(&mut self, _can_assign: bool) -> ExprKind {
+        let mut parts = Vec::new();
+        loop {
+            let token = self.prev.clone();
+            let part = self.unescape(&token);
+            if !part.is_empty() {
+                parts.push(Expr {
+                    kind: ExprKind::Literal(Value::Str(part)),
+                    first: token.clone(),
+                    last: token,
+                });
+            }
+
+            parts.push(self.expression());
+
+            if self.matches(TokenType::Interpolation) {
+                continue;
+            }
+            if !self.matches(TokenType::Str) {
+                self.error_curr("Expected '}' after the interpolated expression");
+                break;
+            }
+            let token = self.prev.clone();
+            let rest = self.unescape(&token);
+            if !rest.is_empty() {
+                parts.push(Expr {
+                    kind: ExprKind::Literal(Value::Str(rest)),
+                    first: token.clone(),
+                    last: token,
+                });
+            }
+            break;
+        }
+        ExprKind::Interpolation(parts)
+    }
+
+    /// Decodes the escape sequences in a string token, reporting the first
+    /// invalid one.
+    fn unescape(&mut self, token: &Token) -> String {
+        let body: Vec<char> = token.lexeme.chars().collect();
+        let body = &body[1..body.len() - 1];
+        let mut value = String::new();
+
+        let mut i = 0;
+        while i < body.len() {
+            if body[i] != '\\' {
+                value.push(body[i]);
+                i += 1;
+                continue;
+            }
+
+            let start = i;
+            i += 2;
+            let decoded = match body.get(start + 1) {
+                Some('n') => Some('\n'),
+                Some('t') => Some('\t'),
+                Some('r') => Some('\r'),
+                Some('0') => Some('\0'),
+                Some(c @ ('\\' | '\'' | '"' | '{' | '}')) => Some(*c),
+                Some('u') if body.get(i) == Some(&'{') => {
+                    match body[i..].iter().position(|&c| c == '}') {
+                        Some(close) => {
+                            let digits: String = body[i + 1..i + close].iter().collect();
+                            i += close + 1;
+                            u32::from_str_radix(&digits, 16)
+                                .ok()
+                                .filter(|_| (1..=6).contains(&digits.len()))
+                                .and_then(char::from_u32)
+                        }
+                        None => None,
+                    }
+                }
+                _ => None,
+            };
+
+            match decoded {
+                Some(c) => value.push(c),
+                None => {
+                    let end = i.min(body.len());
+                    let lines_after = body[end..].iter().filter(|&&c| c == '\n').count();
+                    // The opening quote comes before the body in the token
+                    let before: String = token.lexeme.chars().take(start + 1).collect();
+                    let column = match before.rfind('\n') {
+                        Some(newline) => before[newline + 1..].chars().count() + 1,
+                        None => token.column + start + 1,
+                    };
+                    let lexeme: String = body[start..end].iter().collect();
+                    let offset = token.span.start + before.len();
+                    let escape = Token {
+                        id: TokenType::Str,
+                        span: Span {
+                            start: offset,
+                            end: offset + lexeme.len(),
+                        },
+                        lexeme,
+                        line: token.line - lines_after,
+                        column,
+                    };
+                    self.error_at(escape, "Invalid escape sequence");
+                    break;
+                }
+            }
+        }
+        value
+    }
+
+    fn variable(&mut self, can_assign: bool) -> ExprKind {
+        let name = self.prev.clone();
+
+        if can_assign && self.matches(TokenType::Equal) {
+            let operator = self.prev.clone();
+            let value = self.expression();
+            ExprKind::Assign {
+                name,
+                operator,
+                op: None,
+                value: Box::new(value),
+            }
+        } else if let Some((operator, op)) = self.compound_assignment(can_assign) {
+            let value = self.compound_value(&operator);
+            ExprKind::Assign {
+                name,
+                operator,
+                op: Some(op),
+                value: Box::new(value),
+            }
+        } else {
+            ExprKind::Variable(name)
+        }
+    }
+
+    fn self_kw(&mut self, _can_assign: bool) -> ExprKind {
+        ExprKind::SelfKw(self.prev.clone())
+    }
+
+    fn super_kw(&mut self, _can_assign: bool) -> ExprKind {
+        let keyword = self.prev.clone();
+        self.eat(TokenType::Dot, "Expected '.' after 'super'");
+        self.eat(TokenType::Identifier, "Expected a superclass method name");
+
+        ExprKind::Super {
+            keyword,
+            method: self.prev.clone(),
+        }
+    }
+
+    fn list(&mut self, _can_assign: bool) -> ExprKind {
+        let mut elements = Vec::new();
+
+        self.skip_newlines();
+        while !self.check(TokenType::RightBracket) && !self.check(TokenType::Eof) {
+            elements.push(self.expression());
+
+            self.skip_newlines();
+            if !self.matches(TokenType::Comma) {
+                break;
+            }
+            self.skip_newlines();
+        }
+        self.eat(TokenType::RightBracket, "Expected ']' after list elements");
+
+        ExprKind::List(elements)
+    }
+
+    fn map(&mut self, _can_assign: bool) -> ExprKind {
+        let mut entries = Vec::new();
+
+        self.skip_newlines();
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            let key = self.expression();
+
+            self.skip_newlines();
+            self.eat(TokenType::Colon, "Expected ':' after map key");
+            self.skip_newlines();
+            entries.push((key, self.expression()));
+
+            self.skip_newlines();
+            if !self.matches(TokenType::Comma) {
+                break;
+            }
+            self.skip_newlines();
+        }
+        self.eat(TokenType::RightBrace, "Expected '}' after map entries");
+
+        ExprKind::Map(entries)
+    }
+
+    fn index(&mut self, collection: Expr, can_assign: bool) -> ExprKind {
+        let bracket = self.prev.clone();
+        let index = self.expression();
+        self.eat(TokenType::RightBracket, "Expected ']' after index");
+
+        let collection = Box::new(collection);
+        let index = Box::new(index);
+        let compound = self.compound_assignment(can_assign);
+        if compound.is_some() || can_assign && self.matches(TokenType::Equal) {
+            let (operator, op, value) = match compound {
+                Some((operator, op)) => {
+                    let value = self.compound_value(&operator);
+                    (operator, Some(op), value)
+                }
+                None => (self.prev.clone(), None, self.expression()),
+            };
+            ExprKind::SetIndex {
+                collection,
+                bracket,
+                index,
+                operator,
+                op,
+                value: Box::new(value),
+            }
+        } else {
+            ExprKind::Index {
+                collection,
+                bracket,
+                index,
+            }
+        }
+    }
+
+    fn group(&mut self, _can_assign: bool) -> ExprKind {
+        let expr = self.expression();
+        self.eat(TokenType::RightParen, "Expected closing parenthesis ')'");
+        expr.kind
+    }
+
+    fn unary(&mut self, _can_assign: bool) -> ExprKind {
+        let operator = self.prev.clone();
+        let operand = self.parse_precedence(Precedence::Unary);
+
+        let op = match operator.id {
+            TokenType::Minus => OpCode::Negate,
+            TokenType::Not => OpCode::Not,
+            _ => OpCode::BitNot,
+        };
+        ExprKind::Unary {
+            operator,
+            op,
+            operand: Box::new(operand),
+        }
+    }
+
+    fn binary(&mut self, left: Expr, _can_assign: bool) -> ExprKind {
+        let operator = self.prev.clone();
+
+        // `**` is right-associative, so its right operand may contain another `**`
+        let right = if operator.id == TokenType::StarStar {
+            self.parse_precedence(Precedence::Exponent)
+        } else {
+            let precedence = self.get_rule(operator.id).precedence.next();
+            self.parse_precedence(precedence)
+        };
+
+        let op = match operator.id {
+            TokenType::Plus => OpCode::Add,
+            TokenType::Minus => OpCode::Subtract,
+            TokenType::Star => OpCode::Multiply,
+            TokenType::Slash => OpCode::Divide,
+            TokenType::TildeSlash => OpCode::FloorDivide,
+            TokenType::Percent => OpCode::Modulo,
+            TokenType::StarStar => OpCode::Power,
+            TokenType::Ampersand => OpCode::BitAnd,
+            TokenType::Pipe => OpCode::BitOr,
+            TokenType::Caret => OpCode::BitXor,
+            TokenType::LessLess => OpCode::ShiftLeft,
+            TokenType::GreaterGreater => OpCode::ShiftRight,
+            TokenType::BangEqual | TokenType::EqualEqual => OpCode::Equal,
+            TokenType::Greater | TokenType::LessEqual => OpCode::Greater,
+            TokenType::Less | TokenType::GreaterEqual => OpCode::Less,
+            _ => OpCode::Range,
+        };
+        ExprKind::Binary {
+            operator,
+            op,
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+
+    /// Consumes a compound assignment operator such as `+=`, or `++` or
+    /// `--`, returning it along with the operation it applies.
+    fn compound_assignment(&mut self, can_assign: bool) -> Option<(Token, OpCode)> {
+        if !can_assign {
+            return None;
+        }
+        let op = match self.curr.id {
+            TokenType::PlusEqual => OpCode::Add,
+            TokenType::MinusEqual => OpCode::Subtract,
+            TokenType::StarEqual => OpCode::Multiply,
+            TokenType::SlashEqual => OpCode::Divide,
+            TokenType::PercentEqual => OpCode::Modulo,
+            TokenType::PlusPlus => OpCode::Add,
+            TokenType::MinusMinus => OpCode::Subtract,
+            _ => return None,
+        };
+        self.next();
+        Some((self.prev.clone(), op))
+    }
+
+    /// Parses the right-hand side of a compound assignment. `x++` and `x--`
+    /// are short for `x += 1` and `x -= 1`.
+    fn compound_value(&mut self, operator: &Token) -> Expr {
+        if matches!(operator.id, TokenType::PlusPlus | TokenType::MinusMinus) {
+            return Expr {
+                kind: ExprKind::Literal(Value::Int(1)),
+                first: operator.clone(),
+                last: operator.clone(),
+            };
+        }
+        self.expression()
+    }
+
+    fn contains(&mut self, item: Expr, _can_assign: bool) -> ExprKind {
+        let operator = self.prev.clone();
+        let collection = self.parse_precedence(Precedence::Comparison.next());
+
+        ExprKind::Contains {
+            operator,
+            item: Box::new(item),
+            collection: Box::new(collection),
+        }
+    }
+
+    fn call(&mut self, callee: Expr, _can_assign: bool) -> ExprKind {
+        let paren = self.prev.clone();
+        let (args, names) = self.argument_list();
+
+        ExprKind::Call {
+            callee: Box::new(callee),
+            paren,
+            args,
+            names,
+        }
+    }
+
+    fn dot(&mut self, object: Expr, can_assign: bool) -> ExprKind {
+        self.eat(TokenType::Identifier, "Expected a property name after '.'");
+        let name = self.prev.clone();
+        let object = Box::new(object);
+
+        if can_assign && self.matches(TokenType::Equal) {
+            let operator = self.prev.clone();
+            let value = self.expression();
+            ExprKind::Set {
+                object,
+                name,
+                operator,
+                op: None,
+                value: Box::new(value),
+            }
+        } else if let Some((operator, op)) = self.compound_assignment(can_assign) {
+            let value = self.compound_value(&operator);
+            ExprKind::Set {
+                object,
+                name,
+                operator,
+                op: Some(op),
+                value: Box::new(value),
+            }
+        } else {
+            ExprKind::Get { object, name }
+        }
+    }
+
+    /// Parses the arguments of a call, returning them along with the names of
+    /// the trailing ones that were passed by name.
+    fn argument_list(&mut self) -> (Vec<Expr>, Vec<String>) {
+        let mut args = Vec::new();
+        let mut names = Vec::new();
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if self.check(TokenType::Identifier)
+                    && self.lexer.peek_token().id == TokenType::Colon
+                {
+                    self.next();
+                    names.push(self.prev.lexeme.clone());
+                    self.next();
+                } else if !names.is_empty() {
+                    self.error_curr("Positional arguments cannot follow named arguments");
+                }
+                args.push(self.expression());
+
+                if self.panic_mode || !self.matches(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        if !self.panic_mode && !self.check(TokenType::RightParen) {
+            self.error_curr("Expected ')' after arguments");
+        }
+        if self.panic_mode {
+            self.skip_group(TokenType::RightParen);
+        }
+        self.matches(TokenType::RightParen);
+        (args, names)
+    }
+
+    fn and_op(&mut self, left: Expr, _can_assign: bool) -> ExprKind {
+        let operator = self.prev.clone();
+        let right = self.parse_precedence(Precedence::And);
+        ExprKind::Logical {
+            operator,
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+
+    fn or_op(&mut self, left: Expr, _can_assign: bool) -> ExprKind {
+        let operator = self.prev.clone();
+        let right = self.parse_precedence(Precedence::Or);
+        ExprKind::Logical {
+            operator,
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) -> Expr {
+        if self.at_terminator() {
+            // Leave the terminator for the block it belongs to
+            self.error_curr("Expected expression");
+            return self.error_expr();
+        }
+        self.next();
+        let first = self.prev.clone();
+
+        let prefix_rule = match self.get_rule(first.id).prefix {
+            Some(rule) => rule,
+            None => {
+                self.error("Expected expression");
+                return self.error_expr();
+            }
+        };
+
+        let can_assign = precedence <= Precedence::Assignment;
+        let kind = prefix_rule(self, can_assign);
+        let mut expr = Expr {
+            kind,
+            first: first.clone(),
+            last: self.prev.clone(),
+        };
+
+        while precedence <= self.get_rule(self.curr.id).precedence {
+            self.next();
+            let infix_rule = self.get_rule(self.prev.id).infix.unwrap();
+            let kind = infix_rule(self, expr, can_assign);
+            expr = Expr {
+                kind,
+                first: first.clone(),
+                last: self.prev.clone(),
+            };
+        }
+
+        if can_assign
+            && (self.matches(TokenType::Equal) || self.compound_assignment(true).is_some())
+        {
+            self.error("Invalid assignment target");
+        }
+        expr
+    }
+
+    /// Stands in for an expression that could not be parsed.
+    fn error_expr(&self) -> Expr {
+        Expr {
+            kind: ExprKind::Error,
+            first: self.prev.clone(),
+            last: self.prev.clone(),
+        }
+    }
+
+    fn get_rule(&self, id: TokenType) -> &ParseRule {
+        self.rules.get(&id).unwrap_or(&ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        })
+    }
+
+    /// Parses a type annotation. Class names are resolved by the checker.
+    fn parse_type(&mut self) -> TypeExpr {
+        if self.matches(TokenType::Fun) {
+            if !self.matches(TokenType::LeftParen) {
+                return TypeExpr::Builtin(Type::Fun(None));
+            }
+
+            let mut params = Vec::new();
+            if !self.check(TokenType::RightParen) {
+                loop {
+                    params.push(self.parse_type());
+                    if !self.matches(TokenType::Comma) {
+                        break;
+                    }
+                }
+            }
+            self.eat(TokenType::RightParen, "Expected ')' after parameter types");
+
+            let ret = if self.matches(TokenType::Arrow) {
+                self.parse_type()
+            } else {
+                TypeExpr::Builtin(Type::Any)
+            };
+            return TypeExpr::Fun(params, Box::new(ret));
+        }
+
+        if self.matches(TokenType::LeftBracket) {
+            let element = self.parse_type();
+            self.eat(
+                TokenType::RightBracket,
+                "Expected ']' after list element type",
+            );
+            return TypeExpr::List(Box::new(element));
+        }
+
+        if self.matches(TokenType::LeftBrace) {
+            let key = self.parse_type();
+            let key_end = self.prev.clone();
+            // Built-in key types can be rejected here already, and class
+            // names are left to the checker to resolve
+            if let TypeExpr::Builtin(ty) = &key {
+                if let Err(message) = types::check_key(ty) {
+                    self.error(&message);
+                }
+            }
+            self.eat(TokenType::Colon, "Expected ':' after map key type");
+            let value = self.parse_type();
+            self.eat(TokenType::RightBrace, "Expected '}' after map value type");
+            return TypeExpr::Map {
+                key: Box::new(key),
+                key_end,
+                value: Box::new(value),
+            };
+        }
+
+        if !self.matches(TokenType::Identifier) {
+            self.error_curr("Expected a type");
+            return TypeExpr::Builtin(Type::Any);
+        }
+        let ty = match self.prev.lexeme.as_str() {
+            "any" => Type::Any,
+            "void" => Type::Void,
+            "bool" => Type::Bool,
+            "int" => Type::Int,
+            "float" => Type::Float,
+            "str" => Type::Str,
+            "list" => Type::List(Box::new(Type::Any)),
+            "map" => Type::Map(Box::new(Type::Any), Box::new(Type::Any)),
+            "range" => Type::Range,
+            "error" => Type::Error,
+            "option" => {
+                self.eat(TokenType::LeftBracket, "Expected '[' after 'option'");
+                let value = self.parse_type();
+                self.eat(
+                    TokenType::RightBracket,
+                    "Expected ']' after option value type",
+                );
+                return TypeExpr::Option(Box::new(value));
+            }
+            "result" => {
+                self.eat(TokenType::LeftBracket, "Expected '[' after 'result'");
+                let value = self.parse_type();
+                self.eat(TokenType::Comma, "Expected ',' after result value type");
+                let error = self.parse_type();
+                self.eat(
+                    TokenType::RightBracket,
+                    "Expected ']' after result error type",
+                );
+                return TypeExpr::Result(Box::new(value), Box::new(error));
+            }
+            _ => return TypeExpr::Class(self.prev.clone()),
+        };
+        TypeExpr::Builtin(ty)
+    }
+
+    fn matches(&mut self, id: TokenType) -> bool {
+        if self.check(id) {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.matches(TokenType::Newline) {}
+    }
+
+    fn matches_delimit(&mut self) -> bool {
+        if self.check(TokenType::Semicolon) || self.check(TokenType::Newline) {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn check(&self, id: TokenType) -> bool {
+        self.curr.id == id
+    }
+
+    fn error_curr(&mut self, msg: &str) {
+        self.error_at(self.curr.clone(), msg);
+    }
+
+    fn error(&mut self, msg: &str) {
+        self.error_at(self.prev.clone(), msg);
+    }
+
+    fn error_at(&mut self, token: Token, msg: &str) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            message: msg.to_string(),
+            line: token.line,
+            column: token.column,
+            lexeme: (token.id != TokenType::Eof).then_some(token.lexeme),
+            span: token.span,
+            labels: Vec::new(),
+            notes: Vec::new(),
+        });
+    }
+
+    fn synchronize(&mut self) {
+        use TokenType::*;
+        self.panic_mode = false;
+
+        while self.curr.id != Eof {
+            if self.prev.id == Semicolon || self.prev.id == Newline {
+                // Blank lines would otherwise be taken for a statement
+                self.eat_delimit();
+                return;
+            }
+
+            match self.curr.id {
+                Class | Fun | Var | Let | For | If | While | Do | Try | Throw | Match | Print
+                | Return | Break | Continue | End | Else | Catch | Case => return,
+                _ => (),
+            }
+            self.next();
+        }
+    }
+
+    /// Skips the rest of a bracketed group that has an error in it, up to the
+    /// `close` bracket that ends it. Groups and blocks that open inside it,
+    /// like the body of an anonymous function, are skipped whole. Stops early
+    /// at the end of the line or at an `end` the group cannot contain, as the
+    /// closing bracket is then most likely missing.
+    fn skip_group(&mut self, close: TokenType) {
+        use TokenType::*;
+        let mut brackets = 0;
+        let mut blocks = 0;
+
+        while self.curr.id != Eof {
+            match self.curr.id {
+                id if id == close && brackets == 0 && blocks == 0 => return,
+                LeftParen | LeftBracket | LeftBrace => brackets += 1,
+                RightParen | RightBracket | RightBrace if brackets == 0 => return,
+                RightParen | RightBracket | RightBrace => brackets -= 1,
+                Class | Fun | If | Do | While | For | Try | Match => blocks += 1,
+                End if blocks == 0 => return,
+                End => blocks -= 1,
+                Newline if brackets == 0 && blocks == 0 => return,
+                _ => (),
+            }
+            self.next();
+        }
+    }
+}
+
+/// Parses a script into its statements, along with the syntax errors in it.
+pub fn parse(code: &str) -> (Vec<Stmt>, Vec<Diagnostic>) {
+    let mut parser = Parser::new(code);
+    let program = parser.parse();
+    (program, mem::take(&mut parser.diagnostics))
+}
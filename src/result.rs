@@ -1,4 +1,333 @@
+use crate::token::Span;
+use std::{
+    error::Error,
+    fmt::{self, Display},
+};
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum LangError {
-    CompileError,
-    RuntimeError,
+    /// The script did not compile. Holds every error found, in source order.
+    CompileError(Vec<Diagnostic>),
+    /// The script stopped with an error while running.
+    RuntimeError {
+        message: String,
+        /// The calls that were active, innermost first.
+        trace: Vec<TraceFrame>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found while compiling, pointing at a token of the source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// The line the token ends on.
+    pub line: usize,
+    pub column: usize,
+    /// The text of the token, or `None` at the end of the file.
+    pub lexeme: Option<String>,
+    pub span: Span,
+    /// Other places in the source that explain the problem.
+    pub labels: Vec<Label>,
+    /// Remarks that are not tied to a place in the source.
+    pub notes: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Label {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+    pub lexeme: String,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceFrame {
+    /// The name of the function, or `<script>` for the top level.
+    pub function: String,
+    /// Where the frame was when the error happened. In the outer frames that
+    /// is the call to the next one.
+    pub line: usize,
+    pub column: usize,
+}
+
+impl LangError {
+    /// Formats the error for a terminal. Compile errors quote the lines of
+    /// `source`, which was read from `path`, that they point at. Runtime
+    /// errors quote the line that failed, followed by the trace.
+    pub fn render(&self, source: &str, path: &str) -> String {
+        match self {
+            LangError::CompileError(diagnostics) => {
+                let rendered: Vec<String> = diagnostics
+                    .iter()
+                    .map(|diagnostic| diagnostic.render(source, path))
+                    .collect();
+                rendered.join("\n\n")
+            }
+            LangError::RuntimeError { message, trace } => {
+                let mut lines = vec![format!("{}: {}", Severity::Error, message)];
+                if let Some(frame) = trace.first() {
+                    let pad = " ".repeat(frame.line.to_string().len());
+                    lines.push(format!(
+                        "{}--> {}:{}:{}",
+                        pad, path, frame.line, frame.column
+                    ));
+                    // The line is missing when the function came from an
+                    // earlier source, as in the REPL
+                    if let Some(text) = source.lines().nth(frame.line - 1) {
+                        let indent = " ".repeat(frame.column - 1);
+                        lines.push(format!("{} |", pad));
+                        lines.push(format!("{} | {}", frame.line, text.replace('\t', " ")));
+                        lines.push(format!("{} | {}^", pad, indent));
+                    }
+                }
+                if !trace.is_empty() {
+                    lines.push(String::from("stack backtrace:"));
+                }
+                for (i, frame) in trace.iter().enumerate() {
+                    lines.push(format!(
+                        "{:>4}: {} at {}:{}:{}",
+                        i, frame.function, path, frame.line, frame.column
+                    ));
+                }
+                lines.join("\n")
+            }
+        }
+    }
+}
+
+impl Display for LangError {
+    fn fmt(&self, format: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LangError::CompileError(diagnostics) => {
+                for (i, diagnostic) in diagnostics.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(format)?;
+                    }
+                    write!(format, "{}", diagnostic)?;
+                }
+                Ok(())
+            }
+            LangError::RuntimeError { message, trace } => {
+                write!(format, "{}", message)?;
+                for frame in trace {
+                    write!(
+                        format,
+                        "\n    at {}:{}:{}",
+                        frame.function, frame.line, frame.column
+                    )?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for LangError {}
+
+impl Display for Severity {
+    fn fmt(&self, format: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(format, "error"),
+            Severity::Warning => write!(format, "warning"),
+        }
+    }
+}
+
+/// One line per diagnostic and label, without quoting the source.
+impl Display for Diagnostic {
+    fn fmt(&self, format: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.severity {
+            Severity::Error => write!(format, "[line {}] Error", self.line)?,
+            Severity::Warning => write!(format, "[line {}] Warning", self.line)?,
+        }
+        match &self.lexeme {
+            Some(lexeme) if lexeme == "\n" => write!(format, " at end of line")?,
+            Some(lexeme) => write!(format, " at `{}`", lexeme)?,
+            None => write!(format, " at end of file")?,
+        }
+        write!(format, ": {}", self.message)?;
+        for label in &self.labels {
+            write!(
+                format,
+                "\n[line {}] Note at `{}`: {}",
+                label.line, label.lexeme, label.message
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Where a span is in the source, for quoting it.
+struct Location<'a> {
+    line: usize,
+    column: usize,
+    width: usize,
+    text: &'a str,
+}
+
+impl<'a> Location<'a> {
+    fn find(source: &'a str, span: Span) -> Self {
+        let start = span.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..]
+            .find('\n')
+            .map_or(source.len(), |i| start + i);
+        let end = span.end.clamp(start, line_end);
+        Location {
+            line: source[..start].matches('\n').count() + 1,
+            column: source[line_start..start].chars().count() + 1,
+            width: source[start..end].chars().count().max(1),
+            text: &source[line_start..line_end],
+        }
+    }
+}
+
+impl Diagnostic {
+    /// Formats the diagnostic the way rustc does, quoting the lines of
+    /// `source` it points at and underlining the tokens.
+    pub fn render(&self, source: &str, path: &str) -> String {
+        let primary = Location::find(source, self.span);
+        let mut marks = vec![(primary, '^', None)];
+        for label in &self.labels {
+            let location = Location::find(source, label.span);
+            marks.push((location, '-', Some(label.message.as_str())));
+        }
+        marks.sort_by_key(|(location, _, _)| location.line);
+
+        let gutter = marks.last().unwrap().0.line.to_string().len();
+        let pad = " ".repeat(gutter);
+        let primary = &marks.iter().find(|(_, mark, _)| *mark == '^').unwrap().0;
+
+        let mut lines = vec![
+            format!("{}: {}", self.severity, self.message),
+            format!("{}--> {}:{}:{}", pad, path, primary.line, primary.column),
+            format!("{} |", pad),
+        ];
+        let mut previous = None;
+        for (location, mark, message) in &marks {
+            if previous.is_some_and(|line| location.line > line + 1) {
+                lines.push(String::from("..."));
+            }
+            if previous != Some(location.line) {
+                let text = location.text.replace('\t', " ");
+                lines.push(format!("{:>gutter$} | {}", location.line, text));
+            }
+            let underline = mark.to_string().repeat(location.width);
+            let indent = " ".repeat(location.column - 1);
+            let line = match message {
+                Some(message) => format!("{} | {}{} {}", pad, indent, underline, message),
+                None => format!("{} | {}{}", pad, indent, underline),
+            };
+            lines.push(line);
+            previous = Some(location.line);
+        }
+        if !self.notes.is_empty() {
+            lines.push(format!("{} |", pad));
+        }
+        for note in &self.notes {
+            lines.push(format!("{} = note: {}", pad, note));
+        }
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Diagnostic, Label, LangError, Severity, TraceFrame};
+    use crate::token::Span;
+
+    #[test]
+    fn render() {
+        let source = "let x = 1\nprint x\nx = 'two'\n";
+        let diagnostic = Diagnostic {
+            severity: Severity::Error,
+            message: String::from("Cannot assign twice to immutable variable `x`"),
+            line: 3,
+            column: 5,
+            lexeme: Some(String::from("'two'")),
+            span: Span { start: 22, end: 27 },
+            labels: vec![Label {
+                message: String::from("Declared with 'let' here"),
+                line: 1,
+                column: 5,
+                lexeme: String::from("x"),
+                span: Span { start: 4, end: 5 },
+            }],
+            notes: vec![String::from(
+                "Declare it with 'var' to allow assigning to it",
+            )],
+        };
+
+        let expected = "\
+error: Cannot assign twice to immutable variable `x`
+ --> test.flwm:3:5
+  |
+1 | let x = 1
+  |     - Declared with 'let' here
+...
+3 | x = 'two'
+  |     ^^^^^
+  |
+  = note: Declare it with 'var' to allow assigning to it";
+        assert_eq!(expected, diagnostic.render(source, "test.flwm"));
+    }
+
+    #[test]
+    fn render_end_of_file() {
+        let diagnostic = Diagnostic {
+            severity: Severity::Error,
+            message: String::from("Expected expression"),
+            line: 1,
+            column: 10,
+            lexeme: None,
+            span: Span { start: 9, end: 9 },
+            labels: Vec::new(),
+            notes: Vec::new(),
+        };
+
+        let expected = "\
+error: Expected expression
+ --> test.flwm:1:10
+  |
+1 | print 1 +
+  |          ^";
+        assert_eq!(expected, diagnostic.render("print 1 +", "test.flwm"));
+    }
+
+    #[test]
+    fn render_runtime_error() {
+        let source = "fun f(x)\n    return x ~/ 0\nend\nprint f(1)\n";
+        let frame = |function: &str, line, column| TraceFrame {
+            function: function.to_string(),
+            line,
+            column,
+        };
+        let error = LangError::RuntimeError {
+            message: String::from("Division by zero"),
+            trace: vec![frame("f", 2, 14), frame("<script>", 4, 8)],
+        };
+
+        let expected = "\
+error: Division by zero
+ --> test.flwm:2:14
+  |
+2 |     return x ~/ 0
+  |              ^
+stack backtrace:
+   0: f at test.flwm:2:14
+   1: <script> at test.flwm:4:8";
+        assert_eq!(expected, error.render(source, "test.flwm"));
+        assert_eq!(
+            "Division by zero\n    at f:2:14\n    at <script>:4:8",
+            error.to_string()
+        );
+    }
 }
@@ -1,8 +1,19 @@
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Token {
     pub id: TokenType,
     pub lexeme: String,
+    /// The line the token ends on.
     pub line: usize,
+    /// The column the token starts at, counted in chars from 1.
+    pub column: usize,
+    pub span: Span,
+}
+
+/// A range of bytes in the source code.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
 }
 
 #[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
@@ -11,13 +22,36 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
+    Colon,
     Dot,
+    DotDot,
+    Ellipsis,
     Plus,
+    PlusEqual,
+    PlusPlus,
     Minus,
+    MinusEqual,
+    MinusMinus,
+    Arrow,
     Semicolon,
     Slash,
+    SlashEqual,
     Star,
+    StarEqual,
+    StarStar,
+    Percent,
+    PercentEqual,
+    TildeSlash,
+    Tilde,
+    Ampersand,
+    Pipe,
+    Caret,
+    LessLess,
+    GreaterGreater,
+    Question,
     Newline,
 
     Bang,
@@ -31,6 +65,8 @@ pub enum TokenType {
 
     Identifier,
     Str,
+    RawStr,
+    Interpolation,
     Int,
     Float,
     Bool,
@@ -39,19 +75,32 @@ pub enum TokenType {
     And,
     Not,
     If,
+    Then,
     Else,
     While,
     For,
     Var,
     Let,
+    In,
     Fun,
     Return,
+    Break,
+    Continue,
     Class,
     Super,
     SelfKw,
     Print,
     Do,
     End,
+    Try,
+    Catch,
+    Throw,
+    Match,
+    Case,
+    SomeKw,
+    NoneKw,
+    OkKw,
+    ErrKw,
 
     Error,
     Eof,
@@ -0,0 +1,549 @@
+use crate::{
+    chunk::{OpCode, Value, Variant},
+    token::Token,
+};
+use std::{
+    fmt::{self, Display},
+    rc::Rc,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Type {
+    /// A value whose type is only known at runtime. It is compatible with
+    /// every other type.
+    Any,
+    Void,
+    Bool,
+    Int,
+    Float,
+    /// Either an `int` or a `float`.
+    Number,
+    Str,
+    /// A function, with its signature when it is known at compile time.
+    Fun(Option<Rc<Signature>>),
+    Class(String),
+    Instance(String),
+    /// A list, with the type its elements have in common.
+    List(Box<Type>),
+    /// A map, with the types its keys and values have in common.
+    Map(Box<Type>, Box<Type>),
+    Range,
+    /// An error caught by a `catch` block.
+    Error,
+    /// Either `Some` value of the given type or `None`.
+    Option(Box<Type>),
+    /// Either an `Ok` value of the first type or an `Err` of the second.
+    Result(Box<Type>, Box<Type>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Signature {
+    /// The types of the positional parameters, without the rest parameter.
+    pub params: Vec<Type>,
+    /// The names of the positional parameters. Empty when the signature
+    /// comes from a type annotation, which does not name them.
+    pub names: Vec<String>,
+    /// How many leading parameters have no default value.
+    pub required: usize,
+    /// The element type of the rest parameter, if there is one.
+    pub rest: Option<Type>,
+    pub ret: Type,
+    /// Where the types of unannotated parameters were inferred, for
+    /// diagnostics about the arguments they do not accept.
+    pub inferred_at: Vec<Option<Token>>,
+}
+
+impl Signature {
+    /// A signature whose parameters are all required.
+    pub fn new(params: Vec<Type>, ret: Type) -> Self {
+        Signature {
+            required: params.len(),
+            params,
+            names: Vec::new(),
+            rest: None,
+            ret,
+            inferred_at: Vec::new(),
+        }
+    }
+
+    /// The type of the `index`th positional argument, if a call can have one.
+    pub fn param(&self, index: usize) -> Option<&Type> {
+        self.params.get(index).or(self.rest.as_ref())
+    }
+}
+
+impl Type {
+    pub fn of(value: &Value) -> Self {
+        use Value::*;
+        match value {
+            Void => Type::Void,
+            Bool(_) => Type::Bool,
+            Int(_) | BigInt(_) => Type::Int,
+            Float(_) => Type::Float,
+            Str(_) => Type::Str,
+            Fun(_) | Closure(_) | Native(_) | BoundMethod(_) => Type::Fun(None),
+            Class(class) => Type::Class(class.borrow().name.clone()),
+            Instance(instance) => Type::Instance(instance.borrow().class.borrow().name.clone()),
+            List(_) => Type::List(Box::new(Type::Any)),
+            Map(_) => Type::Map(Box::new(Type::Any), Box::new(Type::Any)),
+            Range(..) => Type::Range,
+            Error(_) => Type::Error,
+            Variant(variant, value) => wrap(*variant, Type::of(value)),
+            // Iterators only live in hidden loop variables
+            Iterator(_) => Type::Any,
+        }
+    }
+
+    /// The type that values of both `self` and `other` have in common.
+    pub fn join(self, other: Type) -> Type {
+        use Type::*;
+        match (self, other) {
+            (List(a), List(b)) => List(Box::new(a.join(*b))),
+            (Map(k1, v1), Map(k2, v2)) => Map(Box::new(k1.join(*k2)), Box::new(v1.join(*v2))),
+            (Option(a), Option(b)) => Option(Box::new(a.join(*b))),
+            (Result(v1, e1), Result(v2, e2)) => {
+                Result(Box::new(v1.join(*v2)), Box::new(e1.join(*e2)))
+            }
+            (Int | Number, Float | Number) | (Float | Number, Int | Number) => Number,
+            (a, b) if a == b => a,
+            _ => Any,
+        }
+    }
+
+    /// The type that values have to have to be both `self` and `other`, or
+    /// `None` when no value can be.
+    pub fn meet(self, other: Type) -> Option<Type> {
+        use Type::*;
+        match (self, other) {
+            (Any, ty) | (ty, Any) => Some(ty),
+            (Number, ty @ (Int | Float)) | (ty @ (Int | Float), Number) => Some(ty),
+            (List(a), List(b)) => Some(List(Box::new(a.meet(*b)?))),
+            (a, b) if a == b => Some(a),
+            _ => None,
+        }
+    }
+
+    /// Whether a value of type `actual` can be stored where `self` is
+    /// expected. `superclass` looks up the superclass of a class by name.
+    pub fn accepts(&self, actual: &Type, superclass: &dyn Fn(&str) -> Option<String>) -> bool {
+        use Type::*;
+        match (self, actual) {
+            (Any, _) | (_, Any) => true,
+            (Fun(None), Fun(_)) | (Fun(_), Fun(None)) => true,
+            (Number, Int | Float) => true,
+            (Fun(Some(expected)), Fun(Some(actual))) => {
+                // Every call the expected signature allows must suit the actual
+                // function, which may take more arguments through defaults.
+                let len = expected.params.len();
+                actual.required <= len
+                    && (len <= actual.params.len() || actual.rest.is_some())
+                    && (expected.rest.is_none() || actual.rest.is_some())
+                    && expected
+                        .params
+                        .iter()
+                        .chain(&expected.rest)
+                        .enumerate()
+                        .all(|(i, e)| actual.param(i).is_some_and(|a| a.accepts(e, superclass)))
+                    && expected.ret.accepts(&actual.ret, superclass)
+            }
+            (List(expected), List(actual)) => expected.accepts(actual, superclass),
+            (Map(expected_key, expected), Map(actual_key, actual)) => {
+                expected_key.accepts(actual_key, superclass) && expected.accepts(actual, superclass)
+            }
+            (Option(expected), Option(actual)) => expected.accepts(actual, superclass),
+            (Result(expected, expected_err), Result(actual, actual_err)) => {
+                expected.accepts(actual, superclass) && expected_err.accepts(actual_err, superclass)
+            }
+            (Instance(expected), Instance(actual)) => {
+                let mut class = Some(actual.clone());
+                while let Some(name) = class {
+                    if &name == expected {
+                        return true;
+                    }
+                    class = superclass(&name);
+                }
+                false
+            }
+            (expected, actual) => expected == actual,
+        }
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, format: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Type::*;
+        match self {
+            Any => write!(format, "any"),
+            Void => write!(format, "void"),
+            Bool => write!(format, "bool"),
+            Int => write!(format, "int"),
+            Float => write!(format, "float"),
+            Number => write!(format, "int or float"),
+            Str => write!(format, "str"),
+            Fun(None) => write!(format, "fun"),
+            Fun(Some(signature)) => {
+                let mut params: Vec<String> =
+                    signature.params.iter().map(|p| p.to_string()).collect();
+                for param in &mut params[signature.required..] {
+                    param.push_str(" = _");
+                }
+                if let Some(rest) = &signature.rest {
+                    params.push(format!("...{}", rest));
+                }
+                write!(format, "fun({}) -> {}", params.join(", "), signature.ret)
+            }
+            Class(name) => write!(format, "class {}", name),
+            Instance(name) => write!(format, "{}", name),
+            List(element) => write!(format, "[{}]", element),
+            Map(key, value) => write!(format, "{{{}: {}}}", key, value),
+            Range => write!(format, "range"),
+            Error => write!(format, "error"),
+            Option(value) => write!(format, "option[{}]", value),
+            Result(value, error) => write!(format, "result[{}, {}]", value, error),
+        }
+    }
+}
+
+/// The operand rules for binary operators. Both the compiler and
+/// `VM::binary_op` go through this function, so a program that type-checks
+/// never hits a runtime type error for an operator. On failure, returns a
+/// description of the operand types the operator expects.
+pub fn binary(op: OpCode, left: &Type, right: &Type) -> Result<Type, &'static str> {
+    use Type::*;
+
+    let numeric = |left: &Type, right: &Type| match (left, right) {
+        (Int, Int) => Some(Int),
+        (Float, Int | Float | Number) | (Int | Number, Float) => Some(Float),
+        (Int | Number, Int | Number) => Some(Number),
+        (Any, Int | Float | Number | Any) | (Int | Float | Number, Any) => Some(Any),
+        _ => None,
+    };
+
+    match op {
+        OpCode::Add => match (left, right) {
+            (Str, Str) | (Str, Any) | (Any, Str) => Ok(Str),
+            (List(a), List(b)) => Ok(List(Box::new(a.as_ref().clone().join(*b.clone())))),
+            (List(_), Any) => Ok(left.clone()),
+            (Any, List(_)) => Ok(right.clone()),
+            _ => numeric(left, right).ok_or("int or float or str or list"),
+        },
+        OpCode::Subtract
+        | OpCode::Multiply
+        | OpCode::Divide
+        | OpCode::FloorDivide
+        | OpCode::Modulo
+        | OpCode::Power => numeric(left, right).ok_or("int or float"),
+        OpCode::BitAnd
+        | OpCode::BitOr
+        | OpCode::BitXor
+        | OpCode::ShiftLeft
+        | OpCode::ShiftRight => match (left, right) {
+            (Int | Any, Int | Any) => Ok(Int),
+            _ => Err("int"),
+        },
+        OpCode::Greater | OpCode::Less => match (left, right) {
+            (Str, Str) | (Str, Any) | (Any, Str) => Ok(Bool),
+            _ => numeric(left, right)
+                .map(|_| Bool)
+                .ok_or("int or float or str"),
+        },
+        OpCode::Equal => Ok(Bool),
+        OpCode::Range => match (left, right) {
+            (Int | Any, Int | Any) => Ok(Range),
+            _ => Err("int"),
+        },
+        _ => panic!("Unsupported binary operation: {:?}", op),
+    }
+}
+
+/// The least restrictive type that an operand of `op` can have alongside
+/// an `other` operand, for inferring the types of parameters from how they
+/// are used. `None` when there is no such type, or `other` is unknown too.
+pub fn binary_operand(op: OpCode, other: &Type) -> Option<Type> {
+    use Type::*;
+    let widest = match other {
+        Any => return None,
+        Int | Float | Number => Number,
+        List(_) => List(Box::new(Any)),
+        other => other.clone(),
+    };
+    [widest, other.clone()]
+        .into_iter()
+        .find(|ty| binary(op, ty, other).is_ok())
+}
+
+/// The operand rules for unary operators, shared in the same way as `binary`.
+pub fn unary(op: OpCode, operand: &Type) -> Result<Type, &'static str> {
+    use Type::*;
+    match op {
+        OpCode::Negate => match operand {
+            Int | Float | Number | Any => Ok(operand.clone()),
+            _ => Err("int or float"),
+        },
+        OpCode::Not => Ok(Bool),
+        OpCode::BitNot => match operand {
+            Int | Any => Ok(Int),
+            _ => Err("int"),
+        },
+        _ => panic!("Unsupported unary operation: {:?}", op),
+    }
+}
+
+/// The rules for reading `collection[index]`, shared in the same way as
+/// `binary`. Returns the type of the element on success.
+pub fn index(collection: &Type, index: &Type) -> Result<Type, String> {
+    use Type::*;
+    match collection {
+        List(element) => match index {
+            Int | Any => Ok(element.as_ref().clone()),
+            other => Err(format!("List indexes must be `int`, found `{}`", other)),
+        },
+        Map(key, value) => {
+            check_key(index)?;
+            if key.accepts(index, &|_| None) {
+                Ok(value.as_ref().clone())
+            } else {
+                Err(format!(
+                    "Keys of `{}` must be `{}`, found `{}`",
+                    collection, key, index
+                ))
+            }
+        }
+        Any => check_key(index).map(|_| Any),
+        other => Err(format!("Cannot index into a value of type `{}`", other)),
+    }
+}
+
+/// Only `int`, `str` and `bool` values can be hashed into map keys.
+pub fn check_key(key: &Type) -> Result<(), String> {
+    use Type::*;
+    match key {
+        Int | Str | Bool | Any => Ok(()),
+        other => Err(format!(
+            "Map keys must be `int`, `str` or `bool`, found `{}`",
+            other
+        )),
+    }
+}
+
+/// The rules for `item in collection`, shared in the same way as `binary`.
+pub fn contains(item: &Type, collection: &Type) -> Result<(), String> {
+    use Type::*;
+    match collection {
+        Map(..) => check_key(item),
+        List(_) | Any => Ok(()),
+        Range => match item {
+            Int | Any => Ok(()),
+            other => Err(format!(
+                "Can only search for an `int` in a `range`, found `{}`",
+                other
+            )),
+        },
+        Str => match item {
+            Str | Any => Ok(()),
+            other => Err(format!(
+                "Can only search for a `str` in a `str`, found `{}`",
+                other
+            )),
+        },
+        other => Err(format!(
+            "Cannot use `in` with a value of type `{}`; expected a `list`, `map`, `range` or `str`",
+            other
+        )),
+    }
+}
+
+/// The rules for `for x in collection`, shared in the same way as `binary`.
+/// Returns the type of the loop variable on success.
+pub fn iterate(collection: &Type) -> Result<Type, String> {
+    use Type::*;
+    match collection {
+        List(element) => Ok(element.as_ref().clone()),
+        Map(key, _) => Ok(key.as_ref().clone()),
+        Str => Ok(Str),
+        Range => Ok(Int),
+        Any => Ok(Any),
+        other => Err(format!("Cannot iterate over a value of type `{}`", other)),
+    }
+}
+
+/// The rules for `throw value`, shared in the same way as `binary`. A `str`
+/// becomes the message of a new error, and an `error` is thrown again.
+pub fn throw(value: &Type) -> Result<(), String> {
+    match value {
+        Type::Str | Type::Error | Type::Any => Ok(()),
+        other => Err(format!(
+            "Can only throw a `str` or an `error`, found `{}`",
+            other
+        )),
+    }
+}
+
+/// The rules for reading `error.name`, shared in the same way as `binary`.
+/// Returns the type of the property on success.
+pub fn error_property(name: &str) -> Result<Type, String> {
+    match name {
+        "message" => Ok(Type::Str),
+        "trace" => Ok(Type::List(Box::new(Type::Str))),
+        _ => Err(format!("`error` has no property `{}`", name)),
+    }
+}
+
+/// The type of `variant(value)` for a `value` of type `value`.
+pub fn wrap(variant: Variant, value: Type) -> Type {
+    let value = Box::new(value);
+    match variant {
+        Variant::Some => Type::Option(value),
+        Variant::None => Type::Option(Box::new(Type::Any)),
+        Variant::Ok => Type::Result(value, Box::new(Type::Any)),
+        Variant::Err => Type::Result(Box::new(Type::Any), value),
+    }
+}
+
+/// The rules for a `case` of a `match` on a value of type `subject`, shared
+/// in the same way as `binary`. Returns the type of the value inside the
+/// variant on success.
+pub fn pattern(variant: Variant, subject: &Type) -> Result<Type, String> {
+    match (variant, subject) {
+        (Variant::Some, Type::Option(value)) => Ok(value.as_ref().clone()),
+        (Variant::None, Type::Option(_)) => Ok(Type::Void),
+        (Variant::Ok, Type::Result(value, _)) => Ok(value.as_ref().clone()),
+        (Variant::Err, Type::Result(_, error)) => Ok(error.as_ref().clone()),
+        (_, Type::Any) => Ok(Type::Any),
+        (variant, subject) => Err(format!(
+            "`{}` does not match a value of type `{}`",
+            variant, subject
+        )),
+    }
+}
+
+/// The variants a `match` on a value of type `subject` has to cover, or
+/// `None` when they are only known at runtime.
+pub fn variants(subject: &Type) -> Option<[Variant; 2]> {
+    match subject {
+        Type::Option(_) => Some([Variant::Some, Variant::None]),
+        Type::Result(..) => Some([Variant::Ok, Variant::Err]),
+        _ => None,
+    }
+}
+
+/// The rules for `value?`, shared in the same way as `binary`. Returns the
+/// type of the value it unwraps to and the type of the value it may return
+/// from the function.
+pub fn propagate(value: &Type) -> Result<(Type, Type), String> {
+    match value {
+        Type::Option(value) => Ok((value.as_ref().clone(), wrap(Variant::None, Type::Any))),
+        Type::Result(value, error) => Ok((
+            value.as_ref().clone(),
+            wrap(Variant::Err, error.as_ref().clone()),
+        )),
+        Type::Any => Ok((Type::Any, Type::Any)),
+        other => Err(format!(
+            "Cannot use '?' on a value of type `{}`; expected an `option` or a `result`",
+            other
+        )),
+    }
+}
+
+/// The arguments of a call matched up with the parameters they are for.
+pub struct Arguments<T> {
+    /// One entry per positional parameter, `None` where its default is used.
+    pub params: Vec<Option<T>>,
+    /// The extra positional arguments collected by the rest parameter.
+    pub rest: Vec<T>,
+}
+
+/// Matches the arguments of a call to the parameters `names`, the first
+/// `required` of which have no default. The last `arg_names.len()` arguments
+/// were passed by name. Both the compiler and `VM::call` go through this
+/// function, so they agree on which calls are valid.
+pub fn bind_arguments<T>(
+    names: &[String],
+    required: usize,
+    variadic: bool,
+    args: Vec<T>,
+    arg_names: &[String],
+) -> Result<Arguments<T>, String> {
+    let positional = args.len() - arg_names.len();
+    if positional > names.len() && !variadic {
+        return Err(format!(
+            "Expected {}{} {}, but found {}",
+            if required < names.len() {
+                "at most "
+            } else {
+                ""
+            },
+            names.len(),
+            plural(names.len(), "argument"),
+            positional
+        ));
+    }
+
+    let mut params: Vec<Option<T>> = names.iter().map(|_| None).collect();
+    let mut rest = Vec::new();
+    let mut args = args.into_iter();
+    for (i, arg) in args.by_ref().take(positional).enumerate() {
+        match params.get_mut(i) {
+            Some(param) => *param = Some(arg),
+            None => rest.push(arg),
+        }
+    }
+
+    let mut unexpected = Vec::new();
+    for (name, arg) in arg_names.iter().zip(args) {
+        match names.iter().position(|param| param == name) {
+            Some(i) if params[i].is_some() => {
+                return Err(format!("Argument `{}` was given more than once", name));
+            }
+            Some(i) => params[i] = Some(arg),
+            None => unexpected.push(name.as_str()),
+        }
+    }
+    if !unexpected.is_empty() {
+        return Err(format!(
+            "Unexpected {} {}",
+            plural(unexpected.len(), "argument"),
+            quote_all(&unexpected)
+        ));
+    }
+
+    let missing: Vec<&str> = (0..required)
+        .filter(|&i| params[i].is_none())
+        .map(|i| names[i].as_str())
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!(
+            "Missing {} for {} {}",
+            plural(missing.len(), "argument"),
+            plural(missing.len(), "parameter"),
+            quote_all(&missing)
+        ));
+    }
+
+    Ok(Arguments { params, rest })
+}
+
+fn plural(len: usize, word: &str) -> String {
+    if len == 1 {
+        word.to_string()
+    } else {
+        format!("{}s", word)
+    }
+}
+
+fn quote_all(names: &[&str]) -> String {
+    let quoted: Vec<String> = names.iter().map(|name| format!("`{}`", name)).collect();
+    quoted.join(", ")
+}
+
+pub fn binary_error(op: &str, left: &Type, right: &Type, expected: &str) -> String {
+    format!(
+        "Cannot use the operator `{op}` with `{left}` and `{right}`; expected two arguments of `{expected}`."
+    )
+}
+
+pub fn unary_error(op: &str, operand: &Type, expected: &str) -> String {
+    format!(
+        "Cannot use the operator `{op}` with `{operand}`; expected an argument of `{expected}`."
+    )
+}
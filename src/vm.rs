@@ -1,44 +1,90 @@
 use crate::{
-    chunk::{type_as_str, OpCode, Value},
-    objects::Function,
-    result::LangError,
+    bigint::BigInt,
+    chunk::{OpCode, Value, Variant},
+    natives,
+    objects::{
+        self, BoundMethod, Class, Closure, Exception, Function, Instance, Key, Map, NativeFunction,
+        Upvalue, ValueIter,
+    },
+    result::{LangError, TraceFrame},
+    types::{self, Type},
 };
-use std::collections::HashMap;
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
 
 const FRAME_LIMIT: usize = 64;
 
-pub type GlobalsType = HashMap<String, Value>;
+/// A `try` block that is running. An error raised inside it drops the
+/// frames and values above `frames` and `stack`, and jumps to `catch` in the
+/// frame below.
+struct Handler {
+    frames: usize,
+    stack: usize,
+    catch: usize,
+}
 
 #[derive(Clone)]
 struct CallFrame {
-    function: Function,
+    closure: Rc<Closure>,
     counter: usize,
     index: usize,
 }
 
 impl CallFrame {
-    fn new(function: Function) -> Self {
+    fn new(closure: Rc<Closure>) -> Self {
         CallFrame {
-            function,
+            closure,
             counter: 0,
             index: 0,
         }
     }
+
+    fn function(&self) -> &Function {
+        &self.closure.function
+    }
 }
 
 pub struct VM {
     frames: Vec<CallFrame>,
     stack: Vec<Value>,
-    globals: GlobalsType,
+    globals: HashMap<String, Value>,
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    handlers: Vec<Handler>,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
-    pub fn new(globals: GlobalsType) -> Self {
-        Self {
+    /// Creates a VM whose globals are the built-in functions.
+    pub fn new() -> Self {
+        let mut vm = Self {
             frames: Vec::new(),
-            stack: vec![Value::Void],
-            globals,
-        }
+            stack: Vec::new(),
+            globals: HashMap::new(),
+            open_upvalues: Vec::new(),
+            handlers: Vec::new(),
+        };
+        natives::define_builtins(&mut vm);
+        vm
+    }
+
+    /// Defines a global function `name` implemented by `function`, which is
+    /// called with exactly `arity` arguments. An `Err` it returns is reported
+    /// as a runtime error.
+    pub fn define_native<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + 'static,
+    {
+        let native = NativeFunction {
+            name: name.to_string(),
+            arity,
+            function: Box::new(function),
+        };
+        self.globals
+            .insert(name.to_string(), Value::Native(Rc::new(native)));
     }
 
     fn push(&mut self, value: Value) {
@@ -73,7 +119,7 @@ impl VM {
     }
 
     fn read_constant(&self, index: usize) -> Value {
-        self.frame().function.chunk.constants[index].clone()
+        self.frame().function().chunk.constants[index].clone()
     }
 
     fn read_string(&self, index: usize) -> String {
@@ -85,75 +131,125 @@ impl VM {
     }
 
     fn binary_op(&mut self, operation: OpCode) -> Result<(), LangError> {
-        use LangError::RuntimeError;
         use OpCode::*;
         use Value::*;
 
-        let mut operands = (self.pop(), self.pop());
-        let bad_operation = |op: &str,
-                             expected: &str,
-                             actual: (Value, Value)|
-         -> Result<(), LangError> {
-            self.runtime_error(&format!(
-                    "Cannot use the operator `{op}` with `{}` and `{}`; expected two arguments of `{expected}`.",
-                    type_as_str(actual.0),
-                    type_as_str(actual.1)
-                ));
-            Err(RuntimeError)
+        let (b, a) = (self.pop(), self.pop());
+        let (left, right) = (Type::of(&a), Type::of(&b));
+        if let Err(expected) = types::binary(operation, &left, &right) {
+            return Err(self.runtime_error(&types::binary_error(
+                operator_symbol(operation),
+                &left,
+                &right,
+                expected,
+            )));
+        }
+
+        let operands = match (a, b) {
+            (Int(a), Float(b)) => (Float(a as f64), Float(b)),
+            (Float(a), Int(b)) => (Float(a), Float(b as f64)),
+            (BigInt(a), Float(b)) => (Float(a.to_f64()), Float(b)),
+            (Float(a), BigInt(b)) => (Float(a), Float(b.to_f64())),
+            operands => operands,
         };
 
-        match operands {
-            (Int(x), Float(_)) => operands.0 = Float(x as f64),
-            (Float(_), Int(x)) => operands.1 = Float(x as f64),
-            _ => (),
+        if let Some(result) = self.integer_op(operation, &operands.0, &operands.1)? {
+            self.push(result);
+            return Ok(());
         }
 
         let result = match operation {
             Add => match operands {
-                (Int(b), Int(a)) => Int(a + b),
-                (Float(b), Float(a)) => Float(a + b),
-                (Str(b), Str(a)) => Str(a + &b),
-                _ => return bad_operation("+", "int or float or str", operands),
+                (Float(a), Float(b)) => Float(a + b),
+                (Str(a), Str(b)) => Str(a + &b),
+                (List(a), List(b)) => {
+                    let mut list = a.borrow().clone();
+                    list.extend(b.borrow().iter().cloned());
+                    List(Rc::new(RefCell::new(list)))
+                }
+                _ => unreachable!(),
             },
             Subtract => match operands {
-                (Int(b), Int(a)) => Int(a - b),
-                (Float(b), Float(a)) => Float(a - b),
-                _ => return bad_operation("-", "int or float", operands),
+                (Float(a), Float(b)) => Float(a - b),
+                _ => unreachable!(),
             },
             Multiply => match operands {
-                (Int(b), Int(a)) => Int(a * b),
-                (Float(b), Float(a)) => Float(a * b),
-                _ => return bad_operation("*", "int or float", operands),
+                (Float(a), Float(b)) => Float(a * b),
+                _ => unreachable!(),
             },
             Divide => match operands {
-                (Int(b), Int(a)) => {
-                    if b == 0 {
-                        self.runtime_error("Division by zero");
-                        return Err(RuntimeError);
+                (Float(a), Float(b)) => {
+                    if b == 0.0 {
+                        return Err(self.runtime_error("Division by zero"));
                     }
-                    Int(a / b)
+                    Float(a / b)
                 }
-                (Float(b), Float(a)) => {
+                _ => unreachable!(),
+            },
+            FloorDivide | Modulo => match operands {
+                (Float(a), Float(b)) => {
                     if b == 0.0 {
-                        self.runtime_error("Division by zero");
-                        return Err(RuntimeError);
+                        return Err(self.runtime_error("Division by zero"));
+                    }
+                    if operation == FloorDivide {
+                        Float((a / b).floor())
+                    } else {
+                        Float(a - b * (a / b).floor())
+                    }
+                }
+                _ => unreachable!(),
+            },
+            Power => match operands {
+                (Float(a), Float(b)) => Float(a.powf(b)),
+                _ => unreachable!(),
+            },
+            BitAnd | BitOr | BitXor => match operands {
+                (Int(a), Int(b)) => Int(match operation {
+                    BitAnd => a & b,
+                    BitOr => a | b,
+                    _ => a ^ b,
+                }),
+                _ => return self.big_int_error(operation),
+            },
+            ShiftLeft | ShiftRight => match operands {
+                (Int(a), Int(b)) => {
+                    let shift = match u32::try_from(b) {
+                        Ok(shift) if shift < isize::BITS => shift,
+                        _ => {
+                            return Err(self.runtime_error(&format!(
+                                "Cannot shift by {} bits; expected 0 to {}",
+                                b,
+                                isize::BITS - 1
+                            )));
+                        }
+                    };
+                    if operation == ShiftRight {
+                        Int(a >> shift)
+                    } else if (a << shift) >> shift == a {
+                        Int(a << shift)
+                    } else {
+                        // Bits would be shifted out, so promote like `*` does
+                        let factor = crate::bigint::BigInt::from(2).pow(shift);
+                        let result = &crate::bigint::BigInt::from(a) * &factor;
+                        Value::from(result)
                     }
-                    Float(a / b)
                 }
-                _ => return bad_operation("/", "int or float", operands),
+                _ => return self.big_int_error(operation),
             },
             Equal => Bool(operands.0 == operands.1),
             Greater => match operands {
-                (Int(b), Int(a)) => Bool(a > b),
-                (Float(b), Float(a)) => Bool(a > b),
-                (Str(b), Str(a)) => Bool(a > b),
-                _ => return bad_operation(">", "int or float or str", operands),
+                (Float(a), Float(b)) => Bool(a > b),
+                (Str(a), Str(b)) => Bool(a > b),
+                _ => unreachable!(),
             },
             Less => match operands {
-                (Int(b), Int(a)) => Bool(a < b),
-                (Float(b), Float(a)) => Bool(a < b),
-                (Str(b), Str(a)) => Bool(a < b),
-                _ => return bad_operation("<", "int or float or str", operands),
+                (Float(a), Float(b)) => Bool(a < b),
+                (Str(a), Str(b)) => Bool(a < b),
+                _ => unreachable!(),
+            },
+            OpCode::Range => match operands {
+                (Int(a), Int(b)) => Value::Range(a, b),
+                _ => return self.big_int_error(operation),
             },
             _ => panic!("Unsupported binary operation: {:?}", operation),
         };
@@ -163,34 +259,335 @@ impl VM {
         Ok(())
     }
 
-    fn call_value(&mut self, value: Value, arg_len: usize) -> Result<CallFrame, LangError> {
+    /// Integer arithmetic and comparisons. Results that do not fit in an
+    /// `isize` are promoted to a `BigInt`, and `Value::from` demotes `BigInt`
+    /// results that fit again, so every integer has a single representation
+    /// whatever the build profile. Returns `None` for other operands and
+    /// operators.
+    fn integer_op(
+        &self,
+        operation: OpCode,
+        a: &Value,
+        b: &Value,
+    ) -> Result<Option<Value>, LangError> {
+        use OpCode::*;
+
+        let big = |value: &Value| match value {
+            Value::Int(value) => Some(Rc::new(BigInt::from(*value))),
+            Value::BigInt(value) => Some(value.clone()),
+            _ => None,
+        };
+        let (big_a, big_b) = match (big(a), big(b)) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return Ok(None),
+        };
+
+        if matches!(operation, Divide | FloorDivide | Modulo) && big_b.is_zero() {
+            return Err(self.runtime_error("Division by zero"));
+        }
+        if operation == Power && big_b.is_negative() {
+            return Err(self.runtime_error(&format!(
+                "Cannot raise an `int` to the negative power {}",
+                b
+            )));
+        }
+
+        if let (Value::Int(a), Value::Int(b)) = (a, b) {
+            let (a, b) = (*a, *b);
+            let result = match operation {
+                Add => a.checked_add(b),
+                Subtract => a.checked_sub(b),
+                Multiply => a.checked_mul(b),
+                Divide => a.checked_div(b),
+                FloorDivide => floor_divide(a, b),
+                Modulo => floor_modulo(a, b),
+                Power => u32::try_from(b).ok().and_then(|b| a.checked_pow(b)),
+                Greater => return Ok(Some(Value::Bool(a > b))),
+                Less => return Ok(Some(Value::Bool(a < b))),
+                _ => return Ok(None),
+            };
+            if let Some(result) = result {
+                return Ok(Some(Value::Int(result)));
+            }
+        }
+
+        let (a, b) = (big_a.as_ref(), big_b.as_ref());
+        let result = match operation {
+            Add => a + b,
+            Subtract => a - b,
+            Multiply => a * b,
+            Divide => a.div_rem(b).0,
+            FloorDivide | Modulo => {
+                let (quotient, remainder) = a.div_rem(b);
+                let adjust = !remainder.is_zero() && remainder.is_negative() != b.is_negative();
+                match (operation, adjust) {
+                    (FloorDivide, true) => &quotient - &BigInt::from(1),
+                    (FloorDivide, false) => quotient,
+                    (_, true) => &remainder + b,
+                    (_, false) => remainder,
+                }
+            }
+            Power => match b.to_isize().and_then(|b| u32::try_from(b).ok()) {
+                Some(exponent) => a.pow(exponent),
+                None => {
+                    return Err(self.runtime_error(&format!("The exponent {} is too large", b)));
+                }
+            },
+            Greater => return Ok(Some(Value::Bool(a > b))),
+            Less => return Ok(Some(Value::Bool(a < b))),
+            _ => return Ok(None),
+        };
+        Ok(Some(Value::from(result)))
+    }
+
+    /// Bitwise operators and ranges only work on integers that fit in an
+    /// `isize`.
+    fn big_int_error<T>(&self, operation: OpCode) -> Result<T, LangError> {
+        Err(self.runtime_error(&format!(
+            "Cannot use the operator `{}` with integers outside of {}..{}",
+            operator_symbol(operation),
+            isize::MIN,
+            isize::MAX
+        )))
+    }
+
+    /// Calls `value` with the `arg_len` values on top of the stack, the last
+    /// `names.len()` of which were passed by name.
+    fn call_value(
+        &mut self,
+        value: Value,
+        arg_len: usize,
+        names: &[String],
+    ) -> Result<(), LangError> {
         match value {
-            Value::Fun(function) => self.call(function, arg_len),
-            _ => {
-                self.runtime_error("Can only call functions and classes");
-                Err(LangError::RuntimeError)
+            Value::Closure(closure) => self.call_closure(closure, arg_len, names),
+            Value::Native(native) => self.call_native(&native, arg_len, names),
+            Value::BoundMethod(bound) => {
+                let receiver = self.stack.len() - arg_len - 1;
+                self.stack[receiver] = bound.receiver.clone();
+                self.call_closure(bound.method.clone(), arg_len, names)
             }
+            Value::Class(class) => {
+                let receiver = self.stack.len() - arg_len - 1;
+                let instance = Instance::new(class.clone());
+                self.stack[receiver] = Value::Instance(Rc::new(RefCell::new(instance)));
+
+                let initializer = class.borrow().methods.get("init").cloned();
+                match initializer {
+                    Some(initializer) => self.call_closure(initializer, arg_len, names),
+                    None => match types::bind_arguments(&[], 0, false, vec![(); arg_len], names) {
+                        Ok(_) => Ok(()),
+                        Err(message) => Err(self.runtime_error(&message)),
+                    },
+                }
+            }
+            _ => Err(self.runtime_error("Can only call functions and classes")),
         }
     }
 
-    fn call(&mut self, function: Function, arg_len: usize) -> Result<CallFrame, LangError> {
-        if arg_len != function.arity {
-            self.runtime_error(&format!(
-                "Expected {} arguments, but found {}",
-                function.arity, arg_len
-            ));
-            Err(LangError::RuntimeError)
-        } else if self.frames.len() >= FRAME_LIMIT {
-            self.runtime_error("Call stack limit exceeded");
-            Err(LangError::RuntimeError)
-        } else {
-            let mut frame = CallFrame::new(function);
-            frame.index = self.stack.len() - arg_len - 1;
-            Ok(frame)
+    fn call_closure(
+        &mut self,
+        closure: Rc<Closure>,
+        arg_len: usize,
+        names: &[String],
+    ) -> Result<(), LangError> {
+        if self.frames.len() >= FRAME_LIMIT {
+            return Err(self.runtime_error("Call stack limit exceeded"));
+        }
+
+        let function = &closure.function;
+        let exact = arg_len == function.arity && names.is_empty();
+        if !exact || function.variadic {
+            self.bind_arguments(&closure, arg_len, names)?;
+        }
+
+        let mut frame = CallFrame::new(closure.clone());
+        frame.index = self.stack.len() - function.arity - function.variadic as usize - 1;
+        self.frames.push(frame);
+        Ok(())
+    }
+
+    fn call_native(
+        &mut self,
+        native: &NativeFunction,
+        arg_len: usize,
+        names: &[String],
+    ) -> Result<(), LangError> {
+        // Native parameters have no names to pass arguments by
+        let params = vec![String::new(); native.arity];
+        let args = vec![(); arg_len];
+        let result = types::bind_arguments(&params, native.arity, false, args, names)
+            .and_then(|_| (native.function)(&self.stack[self.stack.len() - arg_len..]));
+
+        match result {
+            Ok(value) => {
+                self.stack.truncate(self.stack.len() - arg_len - 1);
+                self.push(value);
+                Ok(())
+            }
+            Err(message) => Err(self.runtime_error(&message)),
+        }
+    }
+
+    /// Replaces the arguments on top of the stack with one value for each
+    /// parameter of `closure`, filling in defaults and the rest parameter.
+    fn bind_arguments(
+        &mut self,
+        closure: &Closure,
+        arg_len: usize,
+        names: &[String],
+    ) -> Result<(), LangError> {
+        let function = &closure.function;
+        let args = self.stack.split_off(self.stack.len() - arg_len);
+        let arguments = match types::bind_arguments(
+            &function.params,
+            function.arity - function.defaults,
+            function.variadic,
+            args,
+            names,
+        ) {
+            Ok(arguments) => arguments,
+            Err(message) => {
+                return Err(self.runtime_error(&message));
+            }
+        };
+
+        let first_default = function.arity - function.defaults;
+        for (i, arg) in arguments.params.into_iter().enumerate() {
+            let value = match arg {
+                Some(value) => value,
+                None => {
+                    // The parameters bound so far are on top of the stack
+                    let bound = self.stack[self.stack.len() - i..].to_vec();
+                    self.call(&closure.defaults[i - first_default], &bound)?
+                }
+            };
+            self.push(value);
+        }
+        if function.variadic {
+            self.push(Value::List(Rc::new(RefCell::new(arguments.rest))));
+        }
+        Ok(())
+    }
+
+    /// Checks that `collection[index]` can be read or written at all.
+    fn check_index(&self, collection: &Value, index: &Value) -> Result<(), LangError> {
+        match types::index(&Type::of(collection), &Type::of(index)) {
+            Ok(_) => Ok(()),
+            Err(message) => Err(self.runtime_error(&message)),
+        }
+    }
+
+    /// Returns the position of the element `list[index]`.
+    fn list_index(&self, list: &[Value], index: &Value) -> Result<usize, LangError> {
+        if let Value::Int(index) = *index {
+            if index >= 0 && (index as usize) < list.len() {
+                return Ok(index as usize);
+            }
+        }
+        Err(self.runtime_error(&format!(
+            "Index {} is out of range for a list of length {}",
+            index,
+            list.len()
+        )))
+    }
+
+    fn get_index(&self, collection: &Value, index: &Value) -> Result<Value, LangError> {
+        self.check_index(collection, index)?;
+        match (collection, index) {
+            (Value::List(list), index) => {
+                let list = list.borrow();
+                let position = self.list_index(&list, index)?;
+                Ok(list[position].clone())
+            }
+            (Value::Map(map), index) => {
+                let key = Key::from_value(index).unwrap();
+                match map.borrow().get(&key) {
+                    Some(value) => Ok(value.clone()),
+                    None => {
+                        Err(self.runtime_error(&format!("Key {} is not in the map", index.repr())))
+                    }
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn set_index(&self, collection: &Value, index: &Value, value: Value) -> Result<(), LangError> {
+        self.check_index(collection, index)?;
+        match (collection, index) {
+            (Value::List(list), index) => {
+                let position = self.list_index(&list.borrow(), index)?;
+                list.borrow_mut()[position] = value;
+            }
+            (Value::Map(map), index) => {
+                let key = Key::from_value(index).unwrap();
+                map.borrow_mut().insert(key, value);
+            }
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Evaluates `item in collection`.
+    fn contains(&self, item: &Value, collection: &Value) -> Result<bool, LangError> {
+        if let Err(message) = types::contains(&Type::of(item), &Type::of(collection)) {
+            return Err(self.runtime_error(&message));
+        }
+        Ok(match (item, collection) {
+            (item, Value::List(list)) => list.borrow().contains(item),
+            (item, Value::Map(map)) => map.borrow().contains_key(&Key::from_value(item).unwrap()),
+            (Value::Str(item), Value::Str(string)) => string.contains(item.as_str()),
+            (Value::Int(item), Value::Range(start, end)) => (*start..*end).contains(item),
+            (Value::BigInt(_), Value::Range(..)) => false,
+            _ => unreachable!(),
+        })
+    }
+
+    fn bind_method(&mut self, class: &Rc<RefCell<Class>>, name: &str) -> Result<(), LangError> {
+        let method = match class.borrow().methods.get(name) {
+            Some(method) => method.clone(),
+            None => {
+                return Err(self.runtime_error(&format!("Undefined property `{}`", name)));
+            }
+        };
+
+        let bound = BoundMethod {
+            receiver: self.pop(),
+            method,
+        };
+        self.push(Value::BoundMethod(Rc::new(bound)));
+        Ok(())
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        for upvalue in &self.open_upvalues {
+            if *upvalue.borrow() == Upvalue::Open(slot) {
+                return upvalue.clone();
+            }
         }
+
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+        self.open_upvalues.push(upvalue.clone());
+        upvalue
+    }
+
+    fn close_upvalues(&mut self, last: usize) {
+        let stack = &self.stack;
+        self.open_upvalues.retain(|upvalue| {
+            let slot = match *upvalue.borrow() {
+                Upvalue::Open(slot) => slot,
+                Upvalue::Closed(_) => return false,
+            };
+            if slot < last {
+                return true;
+            }
+            *upvalue.borrow_mut() = Upvalue::Closed(stack[slot].clone());
+            false
+        });
     }
 
-    #[cfg(debug_assertions)]
+    #[cfg(feature = "trace")]
     fn disassemble(&self, op: OpCode) {
         if !self.stack.is_empty() {
             print!("        |  ");
@@ -200,150 +597,626 @@ impl VM {
             println!();
         }
         self.frame()
-            .function
+            .function()
             .chunk
             .disassemble_op(&op, self.frame().counter - 1);
     }
 
-    pub fn run(&mut self, function: Function) -> Result<GlobalsType, LangError> {
-        self.frames.push(CallFrame::new(function));
+    /// Runs a compiled script. Globals it defines stay defined for the next
+    /// script run on this VM.
+    pub fn run(&mut self, function: Function) -> Result<(), LangError> {
+        self.unwind(0, 0);
+        self.push(Value::Void);
 
-        #[cfg(debug_assertions)]
+        let closure = Closure::new(Rc::new(function));
+        self.frames.push(CallFrame::new(Rc::new(closure)));
+
+        #[cfg(feature = "trace")]
         println!("== VM Debug ==");
 
+        match self.execute(0) {
+            Ok(_) => Ok(()),
+            Err(error) => {
+                self.unwind(0, 0);
+                Err(error)
+            }
+        }
+    }
+
+    /// Calls `callee`, which can be any value a script could call, with
+    /// `args` and returns the result.
+    pub fn call(&mut self, callee: &Value, args: &[Value]) -> Result<Value, LangError> {
+        let depth = self.frames.len();
+        let base = self.stack.len();
+        self.push(callee.clone());
+        self.stack.extend_from_slice(args);
+
+        let result = match self.call_value(callee.clone(), args.len(), &[]) {
+            // Natives and classes without an initializer return right away
+            Ok(()) if self.frames.len() == depth => Ok(self.pop()),
+            Ok(()) => self.execute(depth),
+            Err(error) => Err(error),
+        };
+        if result.is_err() {
+            self.unwind(depth, base);
+        }
+        result
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.globals.get(name).cloned()
+    }
+
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.globals.insert(name.to_string(), value);
+    }
+
+    /// Drops the calls above `depth` and the values above `base` after an
+    /// error, so that the VM can be used again.
+    fn unwind(&mut self, depth: usize, base: usize) {
+        self.close_upvalues(base);
+        self.frames.truncate(depth);
+        self.stack.truncate(base);
+        self.drop_handlers(depth);
+    }
+
+    /// Returns `result` from the running call, dropping everything the call
+    /// left on the stack, even in the middle of an expression. Gives back
+    /// `result` if that call is the one `execute` runs above `depth`.
+    fn return_value(&mut self, result: Value, depth: usize) -> Option<Value> {
+        let frame = self.frames.pop().unwrap();
+        self.close_upvalues(frame.index);
+        self.stack.truncate(frame.index);
+        self.drop_handlers(self.frames.len());
+
+        if self.frames.len() == depth {
+            return Some(result);
+        }
+        self.push(result);
+        None
+    }
+
+    /// Forgets the handlers of `try` blocks in the frames above `depth`,
+    /// which have returned or been unwound.
+    fn drop_handlers(&mut self, depth: usize) {
+        while self
+            .handlers
+            .last()
+            .is_some_and(|handler| handler.frames > depth)
+        {
+            self.handlers.pop();
+        }
+    }
+
+    /// Resumes at the `catch` block of the innermost `try` block entered by
+    /// the call to `execute` that runs above `depth`, with the error on the
+    /// stack. Returns the error when there is no such block.
+    fn catch(&mut self, error: LangError, depth: usize) -> Result<(), LangError> {
+        let handler = match self.handlers.last() {
+            Some(handler) if handler.frames > depth => self.handlers.pop().unwrap(),
+            _ => return Err(error),
+        };
+        let exception = match error {
+            LangError::RuntimeError { message, trace } => Exception { message, trace },
+            error => return Err(error),
+        };
+
+        self.unwind(handler.frames, handler.stack);
+        self.frame_mut().counter = handler.catch;
+        self.push(Value::Error(Rc::new(exception)));
+        Ok(())
+    }
+
+    fn error_property(&self, exception: &Exception, name: &str) -> Result<Value, LangError> {
+        if let Err(message) = types::error_property(name) {
+            return Err(self.runtime_error(&message));
+        }
+        Ok(match name {
+            "message" => Value::Str(exception.message.clone()),
+            _ => {
+                let trace = exception.trace.iter().map(|frame| {
+                    Value::Str(format!(
+                        "{}:{}:{}",
+                        frame.function, frame.line, frame.column
+                    ))
+                });
+                Value::from(trace.collect::<Vec<Value>>())
+            }
+        })
+    }
+
+    /// Runs instructions until the call that made the frame count exceed
+    /// `depth` returns, and returns its result. An error resumes at the
+    /// innermost `catch` block this call entered.
+    fn execute(&mut self, depth: usize) -> Result<Value, LangError> {
         loop {
-            let op = self.frame().function.chunk.code[self.frame().counter];
+            match self.step(depth) {
+                Ok(Some(result)) => return Ok(result),
+                Ok(None) => (),
+                Err(error) => self.catch(error, depth)?,
+            }
+        }
+    }
+
+    /// Runs one instruction. Returns the result of the call that made the
+    /// frame count exceed `depth` once that call returns.
+    fn step(&mut self, depth: usize) -> Result<Option<Value>, LangError> {
+        let op = self.frame().function().chunk.code[self.frame().counter];
 
-            self.frame_mut().counter += 1;
+        self.frame_mut().counter += 1;
 
-            #[cfg(debug_assertions)]
-            self.disassemble(op);
+        #[cfg(feature = "trace")]
+        self.disassemble(op);
 
-            use OpCode::*;
-            match op {
-                Constant(index) => {
-                    let constant = self.read_constant(index);
-                    self.push(constant);
+        use OpCode::*;
+        match op {
+            Constant(index) => {
+                let constant = self.read_constant(index);
+                self.push(constant);
+            }
+
+            Add => self.binary_op(Add)?,
+            Subtract => self.binary_op(Subtract)?,
+            Multiply => self.binary_op(Multiply)?,
+            Divide => self.binary_op(Divide)?,
+            FloorDivide => self.binary_op(FloorDivide)?,
+            Modulo => self.binary_op(Modulo)?,
+            Power => self.binary_op(Power)?,
+            BitAnd => self.binary_op(BitAnd)?,
+            BitOr => self.binary_op(BitOr)?,
+            BitXor => self.binary_op(BitXor)?,
+            ShiftLeft => self.binary_op(ShiftLeft)?,
+            ShiftRight => self.binary_op(ShiftRight)?,
+
+            Negate => {
+                let operand = Type::of(&self.peek());
+                if let Err(expected) = types::unary(Negate, &operand) {
+                    return Err(self.runtime_error(&types::unary_error("-", &operand, expected)));
                 }
 
-                Add => self.binary_op(Add)?,
-                Subtract => self.binary_op(Subtract)?,
-                Multiply => self.binary_op(Multiply)?,
-                Divide => self.binary_op(Divide)?,
+                match self.pop() {
+                    Value::Int(value) => match value.checked_neg() {
+                        Some(result) => self.push(Value::Int(result)),
+                        None => self.push(Value::from(-&BigInt::from(value))),
+                    },
+                    Value::BigInt(value) => self.push(Value::from(-value.as_ref())),
+                    Value::Float(value) => self.push(Value::Float(-value)),
+                    _ => unreachable!(),
+                }
+            }
 
-                Negate => match self.peek() {
-                    Value::Int(value) => {
-                        self.pop();
-                        self.push(Value::Int(-value));
-                    }
-                    Value::Float(value) => {
-                        self.pop();
-                        self.push(Value::Float(-value));
+            BitNot => {
+                let operand = Type::of(&self.peek());
+                if let Err(expected) = types::unary(BitNot, &operand) {
+                    return Err(self.runtime_error(&types::unary_error("~", &operand, expected)));
+                }
+
+                match self.pop() {
+                    Value::Int(value) => self.push(Value::Int(!value)),
+                    _ => return self.big_int_error(BitNot),
+                }
+            }
+
+            Not => {
+                let v = self.pop();
+                self.push(Value::Bool(self.is_falsy(v)));
+            }
+
+            Return => {
+                let result = self.pop();
+                return Ok(self.return_value(result, depth));
+            }
+
+            Equal => self.binary_op(Equal)?,
+            Greater => self.binary_op(Greater)?,
+            Less => self.binary_op(Less)?,
+            Range => self.binary_op(Range)?,
+            Print => println!("{}", self.pop()),
+
+            Pop => {
+                self.pop();
+            }
+
+            EndBlock(len) => {
+                let value = self.pop();
+                let first = self.stack.len() - len;
+                self.close_upvalues(first);
+                self.stack.truncate(first);
+                self.push(value);
+            }
+
+            Duplicate(len) => {
+                let top = self.stack[self.stack.len() - len..].to_vec();
+                self.stack.extend(top);
+            }
+
+            Jump(index) => {
+                self.frame_mut().counter += index;
+            }
+
+            JumpIfFalse(index) => {
+                if self.is_falsy(self.peek()) {
+                    self.frame_mut().counter += index;
+                }
+            }
+
+            JumpBack(index) => {
+                self.frame_mut().counter -= index;
+            }
+
+            DefineGlobal(index) => {
+                let name = self.read_string(index);
+                let value = self.pop();
+                self.globals.insert(name, value);
+            }
+
+            GetGlobal(index) => {
+                let name = self.read_string(index);
+                match self.globals.get(&name) {
+                    Some(value) => {
+                        let v = value.clone();
+                        self.push(v);
                     }
-                    value => {
-                        self.runtime_error(&format!(
-                            "Operand of {} must be an `int` or `float`",
-                            value
-                        ));
-                        return Err(LangError::RuntimeError);
+                    None => {
+                        return Err(self.runtime_error(&format!("`{}` is not defined", name)));
                     }
-                },
+                }
+            }
 
-                Not => {
-                    let v = self.pop();
-                    self.push(Value::Bool(self.is_falsy(v)));
+            SetGlobal(index) => {
+                let name = self.read_string(index);
+                if self.globals.insert(name.clone(), self.peek()).is_none() {
+                    self.globals.remove(&name);
+                    return Err(self.runtime_error(&format!("`{}` is not defined", name)));
                 }
+            }
 
-                Return => {
-                    let result = self.pop();
-                    let frame = self.frames.pop();
+            GetLocal(index) => {
+                self.push(self.stack[index + self.frame().index].clone());
+            }
+
+            SetLocal(index) => {
+                let x = index + self.frame().index;
+                self.stack[x] = self.peek();
+            }
+
+            Call(index) => self.call_value(self.peek_more(index), index, &[])?,
 
-                    if self.frames.is_empty() {
-                        return Ok(self.globals.clone());
+            CallNamed(arg_len, index) => {
+                let names: Vec<String> = match self.read_constant(index) {
+                    Value::List(names) => {
+                        names.borrow().iter().map(|name| name.to_string()).collect()
                     }
+                    _ => panic!("Constant is not a list of names"),
+                };
+                self.call_value(self.peek_more(arg_len), arg_len, &names)?
+            }
+
+            Closure(index) => {
+                let function = match self.read_constant(index) {
+                    Value::Fun(function) => function,
+                    _ => panic!("Constant is not a function"),
+                };
 
-                    self.stack.truncate(frame.unwrap().index);
-                    self.push(result);
+                let mut closure = objects::Closure::new(function.clone());
+                closure.defaults = self.stack.split_off(self.stack.len() - function.defaults);
+                for upvalue in &function.upvalues {
+                    let captured = if upvalue.is_local {
+                        self.capture_upvalue(self.frame().index + upvalue.index)
+                    } else {
+                        self.frame().closure.upvalues[upvalue.index].clone()
+                    };
+                    closure.upvalues.push(captured);
                 }
 
-                Equal => self.binary_op(Equal)?,
-                Greater => self.binary_op(Greater)?,
-                Less => self.binary_op(Less)?,
-                Print => println!("{}", self.pop()),
+                self.push(Value::Closure(Rc::new(closure)));
+            }
 
-                Pop => {
-                    self.pop();
-                }
+            GetUpvalue(index) => {
+                let upvalue = self.frame().closure.upvalues[index].clone();
+                let value = match &*upvalue.borrow() {
+                    Upvalue::Open(slot) => self.stack[*slot].clone(),
+                    Upvalue::Closed(value) => value.clone(),
+                };
+                self.push(value);
+            }
 
-                Jump(index) => {
-                    self.frame_mut().counter += index;
+            SetUpvalue(index) => {
+                let upvalue = self.frame().closure.upvalues[index].clone();
+                let value = self.peek();
+                match &mut *upvalue.borrow_mut() {
+                    Upvalue::Open(slot) => self.stack[*slot] = value,
+                    Upvalue::Closed(closed) => *closed = value,
+                };
+            }
+
+            CloseUpvalue => {
+                self.close_upvalues(self.stack.len() - 1);
+                self.pop();
+            }
+
+            Class(index) => {
+                let class = objects::Class::new(self.read_string(index));
+                self.push(Value::Class(Rc::new(RefCell::new(class))));
+            }
+
+            GetProperty(index) => {
+                let instance = match self.peek() {
+                    Value::Instance(instance) => instance,
+                    Value::Error(exception) => {
+                        let name = self.read_string(index);
+                        let property = self.error_property(&exception, &name)?;
+                        self.pop();
+                        self.push(property);
+                        return Ok(None);
+                    }
+                    _ => {
+                        return Err(self.runtime_error("Only instances have properties"));
+                    }
+                };
+
+                let name = self.read_string(index);
+                let field = instance.borrow().fields.get(&name).cloned();
+                match field {
+                    Some(value) => {
+                        self.pop();
+                        self.push(value);
+                    }
+                    None => {
+                        let class = instance.borrow().class.clone();
+                        self.bind_method(&class, &name)?;
+                    }
                 }
+            }
 
-                JumpIfFalse(index) => {
-                    if self.is_falsy(self.peek()) {
-                        self.frame_mut().counter += index;
+            SetProperty(index) => {
+                let instance = match self.peek_more(1) {
+                    Value::Instance(instance) => instance,
+                    _ => {
+                        return Err(self.runtime_error("Only instances have fields"));
                     }
+                };
+
+                let name = self.read_string(index);
+                let value = self.pop();
+                instance.borrow_mut().fields.insert(name, value.clone());
+                self.pop();
+                self.push(value);
+            }
+
+            Method(index) => {
+                let name = self.read_string(index);
+                if let (Value::Class(class), Value::Closure(method)) =
+                    (self.peek_more(1), self.peek())
+                {
+                    class.borrow_mut().methods.insert(name, method);
                 }
+                self.pop();
+            }
 
-                JumpBack(index) => {
-                    self.frame_mut().counter -= index;
+            Inherit => {
+                let superclass = match self.peek_more(1) {
+                    Value::Class(superclass) => superclass,
+                    _ => {
+                        return Err(self.runtime_error("Superclass must be a class"));
+                    }
+                };
+
+                if let Value::Class(subclass) = self.peek() {
+                    let methods = superclass.borrow().methods.clone();
+                    subclass.borrow_mut().methods.extend(methods);
                 }
+                self.pop();
+            }
 
-                DefineGlobal(index) => {
-                    let name = self.read_string(index);
-                    let value = self.pop();
-                    self.globals.insert(name, value);
+            GetSuper(index) => {
+                let name = self.read_string(index);
+                if let Value::Class(superclass) = self.pop() {
+                    self.bind_method(&superclass, &name)?;
                 }
+            }
 
-                GetGlobal(index) => {
-                    let name = self.read_string(index);
-                    match self.globals.get(&name) {
-                        Some(value) => {
-                            let v = value.clone();
-                            self.push(v);
-                        }
+            BuildList(len) => {
+                let items = self.stack.split_off(self.stack.len() - len);
+                self.push(Value::List(Rc::new(RefCell::new(items))));
+            }
+
+            BuildMap(len) => {
+                let items = self.stack.split_off(self.stack.len() - len * 2);
+                let mut map = Map::new();
+                for entry in items.chunks(2) {
+                    match Key::from_value(&entry[0]) {
+                        Some(key) => map.insert(key, entry[1].clone()),
                         None => {
-                            self.runtime_error(&format!("`{}` is not defined", name));
-                            return Err(LangError::RuntimeError);
+                            let message = types::check_key(&Type::of(&entry[0])).unwrap_err();
+                            return Err(self.runtime_error(&message));
                         }
                     }
                 }
+                self.push(Value::Map(Rc::new(RefCell::new(map))));
+            }
 
-                SetGlobal(index) => {
-                    let name = self.read_string(index);
-                    if self.globals.insert(name.clone(), self.peek()).is_none() {
-                        self.globals.remove(&name);
-                        self.runtime_error(&format!("`{}` is not defined", name));
-                        return Err(LangError::RuntimeError);
+            Interpolate(len) => {
+                let parts = self.stack.split_off(self.stack.len() - len);
+                let string: String = parts.iter().map(|part| part.to_string()).collect();
+                self.push(Value::Str(string));
+            }
+
+            GetIndex => {
+                let index = self.pop();
+                let collection = self.pop();
+                let item = self.get_index(&collection, &index)?;
+                self.push(item);
+            }
+
+            SetIndex => {
+                let value = self.pop();
+                let index = self.pop();
+                let collection = self.pop();
+                self.set_index(&collection, &index, value.clone())?;
+                self.push(value);
+            }
+
+            Iter => {
+                let value = self.pop();
+                match ValueIter::new(&value) {
+                    Some(iterator) => self.push(Value::Iterator(Rc::new(RefCell::new(iterator)))),
+                    None => {
+                        let message = types::iterate(&Type::of(&value)).unwrap_err();
+                        return Err(self.runtime_error(&message));
                     }
                 }
+            }
 
-                GetLocal(index) => {
-                    self.push(self.stack[index + self.frame().index].clone());
+            ForIter(index) => {
+                let next = match self.peek() {
+                    Value::Iterator(iterator) => iterator.borrow_mut().next(),
+                    _ => unreachable!(),
+                };
+                match next {
+                    Some(value) => self.push(value),
+                    None => self.frame_mut().counter += index,
                 }
+            }
 
-                SetLocal(index) => {
-                    let x = index + self.frame().index;
-                    self.stack[x] = self.peek();
-                }
+            In => {
+                let collection = self.pop();
+                let item = self.pop();
+                let found = self.contains(&item, &collection)?;
+                self.push(Value::Bool(found));
+            }
+
+            Try(index) => {
+                let handler = Handler {
+                    frames: self.frames.len(),
+                    stack: self.stack.len(),
+                    catch: self.frame().counter + index,
+                };
+                self.handlers.push(handler);
+            }
+
+            EndTry => {
+                self.handlers.pop();
+            }
+
+            Wrap(variant) => {
+                let value = self.pop();
+                self.push(Value::Variant(variant, Rc::new(value)));
+            }
 
-                Call(index) => {
-                    let frame = self.call_value(self.peek_more(index), index)?;
-                    self.frames.push(frame);
+            Match(variant, index) => match self.pop() {
+                Value::Variant(actual, value) if actual == variant => {
+                    if variant != Variant::None {
+                        self.push(value.as_ref().clone());
+                    }
+                }
+                Value::Variant(..) => self.frame_mut().counter += index,
+                other => {
+                    let message = types::pattern(variant, &Type::of(&other)).unwrap_err();
+                    return Err(self.runtime_error(&message));
                 }
+            },
+
+            NoMatch => {
+                let value = self.pop();
+                let message = format!("No case matches the value {}", value.repr());
+                return Err(self.runtime_error(&message));
             }
+
+            Propagate => match self.peek() {
+                Value::Variant(Variant::Some | Variant::Ok, value) => {
+                    self.pop();
+                    self.push(value.as_ref().clone());
+                }
+                Value::Variant(..) => {
+                    let result = self.pop();
+                    return Ok(self.return_value(result, depth));
+                }
+                other => {
+                    let message = types::propagate(&Type::of(&other)).unwrap_err();
+                    return Err(self.runtime_error(&message));
+                }
+            },
+
+            Throw => match self.pop() {
+                Value::Str(message) => return Err(self.runtime_error(&message)),
+                Value::Error(exception) => return Err(exception.to_error()),
+                other => {
+                    let message = types::throw(&Type::of(&other)).unwrap_err();
+                    return Err(self.runtime_error(&message));
+                }
+            },
         }
+        Ok(None)
     }
 
-    fn runtime_error(&self, msg: &str) {
-        eprintln!("{}", msg);
+    /// Builds the error for `msg` with a trace of the active calls.
+    fn runtime_error(&self, msg: &str) -> LangError {
+        let trace = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                // The counter is past the instruction that is running, which
+                // in the outer frames is the call
+                let chunk = &frame.function().chunk;
+                let op = frame.counter.saturating_sub(1);
+                TraceFrame {
+                    function: frame.function().name.clone(),
+                    line: chunk.lines[op],
+                    column: chunk.columns[op],
+                }
+            })
+            .collect();
 
-        for frame in self.frames.iter().rev() {
-            eprintln!(
-                "    at {}:{}",
-                frame.function.name,
-                frame.function.chunk.lines.last().unwrap()
-            );
+        LangError::RuntimeError {
+            message: msg.to_string(),
+            trace,
         }
     }
 }
+
+fn operator_symbol(operation: OpCode) -> &'static str {
+    use OpCode::*;
+    match operation {
+        Add => "+",
+        Subtract => "-",
+        Multiply => "*",
+        Divide => "/",
+        FloorDivide => "~/",
+        Modulo => "%",
+        Power => "**",
+        BitAnd => "&",
+        BitOr => "|",
+        BitXor => "^",
+        ShiftLeft => "<<",
+        ShiftRight => ">>",
+        Equal => "==",
+        Greater => ">",
+        Less => "<",
+        Range => "..",
+        BitNot => "~",
+        _ => panic!("Unsupported binary operation: {:?}", operation),
+    }
+}
+
+/// Integer division that rounds towards negative infinity, so that
+/// `a == floor_divide(a, b) * b + floor_modulo(a, b)`.
+fn floor_divide(a: isize, b: isize) -> Option<isize> {
+    let quotient = a.checked_div(b)?;
+    if a % b != 0 && (a < 0) != (b < 0) {
+        Some(quotient - 1)
+    } else {
+        Some(quotient)
+    }
+}
+
+/// The remainder of `floor_divide`, which has the same sign as `b`.
+fn floor_modulo(a: isize, b: isize) -> Option<isize> {
+    let remainder = a.checked_rem(b)?;
+    if remainder != 0 && (remainder < 0) != (b < 0) {
+        Some(remainder + b)
+    } else {
+        Some(remainder)
+    }
+}
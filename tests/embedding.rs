@@ -0,0 +1,125 @@
+use flowim::{Key, LangError, Value, Variant, VM};
+
+fn run(vm: &mut VM, source: &str) -> Result<(), LangError> {
+    vm.run(flowim::compile(source)?)
+}
+
+#[test]
+fn globals() {
+    let mut vm = VM::new();
+    vm.set_global("name", Value::from("Flowim"));
+    run(&mut vm, "var greeting = 'Hello, {name}!'").unwrap();
+    assert_eq!(
+        "Hello, Flowim!",
+        vm.get_global("greeting").unwrap().to_string()
+    );
+
+    // Globals stay defined for later scripts
+    run(&mut vm, "greeting = greeting + '!'").unwrap();
+    assert_eq!(
+        "Hello, Flowim!!",
+        vm.get_global("greeting").unwrap().to_string()
+    );
+    assert!(vm.get_global("missing").is_none());
+}
+
+#[test]
+fn maps() {
+    let mut vm = VM::new();
+    run(&mut vm, "var ages = {'Ada': 36}").unwrap();
+    let Some(Value::Map(ages)) = vm.get_global("ages") else {
+        panic!("`ages` should be a map");
+    };
+    let ada = Key::Str(String::from("Ada"));
+    assert_eq!("36", ages.borrow().get(&ada).unwrap().to_string());
+
+    ages.borrow_mut()
+        .insert(Key::Str(String::from("Alan")), Value::from(41));
+    run(&mut vm, "ages['Alan'] = ages['Alan'] + 1").unwrap();
+    let alan = Key::Str(String::from("Alan"));
+    assert_eq!("42", ages.borrow().get(&alan).unwrap().to_string());
+}
+
+#[test]
+fn variants() {
+    let mut vm = VM::new();
+    run(&mut vm, "var found = Some(3)\nvar failed = Err('no')").unwrap();
+    let Some(Value::Variant(Variant::Some, value)) = vm.get_global("found") else {
+        panic!("`found` should be `Some`");
+    };
+    assert_eq!("3", value.to_string());
+    assert!(matches!(
+        vm.get_global("failed"),
+        Some(Value::Variant(Variant::Err, _))
+    ));
+}
+
+#[test]
+fn calls() {
+    let mut vm = VM::new();
+    run(
+        &mut vm,
+        "fun add(a, b = 10) return a + b end
+        class Point
+            fun init(x)
+                self.x = x
+            end
+        end",
+    )
+    .unwrap();
+
+    let add = vm.get_global("add").unwrap();
+    let sum = vm.call(&add, &[Value::from(1), Value::from(2)]).unwrap();
+    assert_eq!("3", sum.to_string());
+    assert_eq!("11", vm.call(&add, &[Value::from(1)]).unwrap().to_string());
+
+    let point = vm.get_global("Point").unwrap();
+    let instance = vm.call(&point, &[Value::from(5)]).unwrap();
+    assert_eq!("<Point instance>", instance.to_string());
+
+    let len = vm.get_global("len").unwrap();
+    let list = Value::from(vec![Value::from(true), Value::from(1.5)]);
+    assert_eq!("2", vm.call(&len, &[list]).unwrap().to_string());
+}
+
+#[test]
+fn natives() {
+    let mut vm = VM::new();
+    vm.define_native("shout", 1, |args| match &args[0] {
+        Value::Str(text) => Ok(Value::Str(text.to_uppercase())),
+        _ => Err(String::from("Expected a string")),
+    });
+
+    run(&mut vm, "var loud = shout('hey')").unwrap();
+    assert_eq!("HEY", vm.get_global("loud").unwrap().to_string());
+
+    match run(&mut vm, "fun f()\n    shout(1)\nend\nf()") {
+        Err(LangError::RuntimeError { message, trace }) => {
+            assert_eq!("Expected a string", message);
+            let frames: Vec<(&str, usize, usize)> = trace
+                .iter()
+                .map(|frame| (frame.function.as_str(), frame.line, frame.column))
+                .collect();
+            assert_eq!(vec![("f", 2, 10), ("<script>", 4, 2)], frames);
+        }
+        _ => panic!("Expected a runtime error"),
+    }
+
+    // The VM can still be used after an error
+    let shout = vm.get_global("shout").unwrap();
+    let result = vm.call(&shout, &[Value::from("ok")]).unwrap();
+    assert_eq!("OK", result.to_string());
+}
+
+#[test]
+fn compile_errors() {
+    let diagnostics = match flowim::compile("var x: int = 'a'\nprint y +") {
+        Err(LangError::CompileError(diagnostics)) => diagnostics,
+        _ => panic!("Expected a compile error"),
+    };
+    assert_eq!(2, diagnostics.len());
+    assert_eq!(1, diagnostics[0].line);
+    assert_eq!(Some(String::from("'a'")), diagnostics[0].lexeme);
+    assert_eq!(2, diagnostics[1].line);
+    assert_eq!(None, diagnostics[1].lexeme);
+}
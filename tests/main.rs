@@ -1,5 +1,6 @@
 #![cfg(test)]
 
+use flowim::LangError;
 use regex::Regex;
 use std::{env, fs, process::Command};
 use test_generator::test_resources;
@@ -11,7 +12,7 @@ struct Expected<'a> {
     runtime_error: &'a str,
 }
 
-fn parse_comments(contents: &str) -> Expected {
+fn parse_comments(contents: &str) -> Expected<'_> {
     let output_regex = Regex::new(r"//> (.*)").unwrap();
     let compile_error_regex = Regex::new(r"//! (.*)").unwrap();
     let runtime_error_regex = Regex::new(r"//!! (.*)").unwrap();
@@ -39,6 +40,12 @@ fn parse_comments(contents: &str) -> Expected {
     expected
 }
 
+/// The line a diagnostic such as `[line 3] Error at ...` is on.
+fn line_number(diagnostic: &str) -> usize {
+    let regex = Regex::new(r"^\[line (\d+)\]").unwrap();
+    regex.captures(diagnostic).unwrap()[1].parse().unwrap()
+}
+
 #[test_resources("tests/**/*.flwm")]
 fn run(resource: &str) {
     let contents = fs::read_to_string(resource).expect("Could not read test file");
@@ -46,7 +53,7 @@ fn run(resource: &str) {
     let expected = parse_comments(&contents);
 
     let path = env!("CARGO_BIN_EXE_flowim");
-    let mut path = Command::new(&path);
+    let mut path = Command::new(path);
 
     let result = path.arg(resource).output().unwrap();
 
@@ -54,8 +61,23 @@ fn run(resource: &str) {
     let out: Vec<&str> = out.lines().collect();
 
     let err = String::from_utf8(result.stderr).unwrap();
-    let _err: Vec<&str> = err.lines().collect();
+    let err: Vec<&str> = err.lines().collect();
 
     assert_eq!(out, expected.output);
-    // TODO: Add support for error tests once error messages are finalized
+
+    // Compile errors are compared in their one-line form, which the binary
+    // only prints as part of the rendered diagnostics. Notes can point at
+    // earlier lines than their error, so both lists are in line order.
+    let compile_errors = match flowim::compile(&contents) {
+        Err(error @ LangError::CompileError(_)) => error.to_string(),
+        _ => String::new(),
+    };
+    let mut compile_errors: Vec<&str> = compile_errors.lines().collect();
+    compile_errors.sort_by_key(|error| line_number(error));
+    assert_eq!(compile_errors, expected.compile_error);
+
+    if !expected.runtime_error.is_empty() {
+        let message = err.first().and_then(|line| line.strip_prefix("error: "));
+        assert_eq!(message, Some(expected.runtime_error));
+    }
 }